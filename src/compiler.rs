@@ -1,58 +1,240 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::error;
+use std::fmt;
+use std::str::FromStr;
 
-use super::parser::Token;
-use super::machine::Instruction;
+use super::Register;
+use super::parser::{ParseFailure, Parser, Span, Token};
+use super::machine::{Input, Instruction, Machine, Registers, TileKind};
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub enum Error<E> {
     ParserError(E),
-    UndefinedLabel
+    // The index is of the token in the parser's output. The span is
+    // only known when compiling spanned tokens.
+    UndefinedLabel { label: String, token_index: usize, span: Option<Span> },
+    DuplicateLabel(String),
+    InvalidJumpTarget(usize),
+    MissingHeader,
 }
 
-impl<E> From<E> for Error<E> {
-    fn from(e: E) -> Error<E> {
+// Not a `From` impl, as one for every `E` would rule out converting
+// from any other error type
+impl<E> Error<E> {
+    pub fn parser(e: E) -> Error<E> {
         Error::ParserError(e)
     }
 }
 
+impl<E: fmt::Display> fmt::Display for Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::ParserError(ref e) => write!(f, "could not parse the program {}", e),
+            Error::UndefinedLabel { ref label, .. } => write!(f, "undefined label {}", label),
+            Error::DuplicateLabel(ref label) => write!(f, "label {} is defined more than once", label),
+            Error::InvalidJumpTarget(i) => write!(f, "jump to instruction {}, which doesn't exist", i),
+            Error::MissingHeader => "the program doesn't start with the header".fmt(f),
+        }
+    }
+}
+
+impl<E: error::Error + 'static> error::Error for Error<E> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::ParserError(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// `require_header` rejects programs that don't start with the
+// `-- HUMAN RESOURCE MACHINE PROGRAM --` line the game exports.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Options {
+    pub require_header: bool,
+}
+
+// Each lint holds the index of the instruction it is about
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Lint {
+    // The accumulator is read while it may still be empty
+    EmptyAccumulator(usize),
+    // A `COPYTO` whose tile is never read before it is replaced
+    DeadStore(usize),
+    // A `JUMP` that goes straight back to itself
+    SelfLoop(usize),
+}
+
+impl Lint {
+    pub fn index(&self) -> usize {
+        match *self {
+            Lint::EmptyAccumulator(i) |
+            Lint::DeadStore(i) |
+            Lint::SelfLoop(i) => i,
+        }
+    }
+}
+
+// An instruction that needs a number may be given something else.
+// `found` is `None` when the kind can't be known, such as for a tile
+// straight from the inbox.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct TypeWarning {
+    pub index: usize,
+    pub expected: TileKind,
+    pub found: Option<TileKind>,
+}
+
+// What is known about the kind of each tile at one point in the
+// program. A register that is missing, like an accumulator of `None`,
+// could hold anything.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct KindState {
+    accumulator: Option<TileKind>,
+    registers: BTreeMap<u8, TileKind>,
+}
+
+impl KindState {
+    fn register(&self, r: Register) -> Option<TileKind> {
+        match r {
+            Register::Direct(r) => self.registers.get(&r).cloned(),
+            Register::Indirect(..) => None,
+        }
+    }
+
+    fn set_register(&mut self, r: Register, kind: Option<TileKind>) {
+        match (r, kind) {
+            (Register::Direct(r), Some(kind)) => { self.registers.insert(r, kind); },
+            (Register::Direct(r), None) => { self.registers.remove(&r); },
+            // Any register could have been written
+            (Register::Indirect(..), _) => self.registers.clear(),
+        }
+    }
+
+    // Keeps only what is true for both
+    fn merge(&self, other: &KindState) -> KindState {
+        KindState {
+            accumulator: if self.accumulator == other.accumulator { self.accumulator } else { None },
+            registers: self.registers.iter()
+                .filter(|&(r, k)| other.registers.get(r) == Some(k))
+                .map(|(&r, &k)| (r, k))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct Program(Vec<Instruction>);
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Program {
+    instructions: Vec<Instruction>,
+    comments: BTreeMap<String, String>,
+    // Each comment marker and the index of the instruction it
+    // precedes, in source order
+    comment_positions: Vec<(usize, String)>,
+    // Where each instruction came from in the source. Empty if the
+    // program wasn't compiled from spanned tokens.
+    spans: Vec<Span>,
+}
 
 impl Program {
     pub fn compile<'a, I, E>(iterator: I) -> Result<Program, Error<E>>
         where I: IntoIterator<Item = Result<Token<'a>, E>>
+    {
+        Program::compile_with_options(iterator, Options::default())
+    }
+
+    pub fn compile_with_options<'a, I, E>(iterator: I, options: Options) -> Result<Program, Error<E>>
+        where I: IntoIterator<Item = Result<Token<'a>, E>>
     {
         // Find any parsing failures
-        let tokens: Vec<_> = try!(iterator.into_iter().collect());
-
-        // Remove values that don't change the behavior
-        let without_junk: Vec<_> = tokens.into_iter().filter(|t| match *t {
-            Token::Header |
-            Token::Comment(..) |
-            Token::CommentDefinition(..) |
-            Token::Whitespace(..) => false,
-            _ => true,
-        }).collect();
+        let tokens: Vec<_> = try!(iterator.into_iter().collect::<Result<_, _>>().map_err(Error::parser));
+        let mut program = try!(Program::compile_tokens(tokens.into_iter().map(|t| (t, None)), options));
+        program.spans.clear();
+        Ok(program)
+    }
+
+    pub fn compile_spanned<'a, I, E>(iterator: I) -> Result<Program, Error<E>>
+        where I: IntoIterator<Item = Result<(Token<'a>, Span), E>>
+    {
+        Program::compile_spanned_with_options(iterator, Options::default())
+    }
+
+    pub fn compile_spanned_with_options<'a, I, E>(iterator: I, options: Options) -> Result<Program, Error<E>>
+        where I: IntoIterator<Item = Result<(Token<'a>, Span), E>>
+    {
+        let tokens: Vec<_> = try!(iterator.into_iter().collect::<Result<_, _>>().map_err(Error::parser));
+        Program::compile_tokens(tokens.into_iter().map(|(t, span)| (t, Some(span))), options)
+    }
+
+    fn compile_tokens<'a, I, E>(tokens: I, options: Options) -> Result<Program, Error<E>>
+        where I: IntoIterator<Item = (Token<'a>, Option<Span>)>
+    {
+        let tokens: Vec<_> = tokens.into_iter().collect();
+
+        if options.require_header {
+            let first = tokens.iter().map(|&(ref t, _)| t).find(|t| match **t {
+                Token::Whitespace(..) => false,
+                _ => true,
+            });
+            match first {
+                Some(&Token::Header) => {},
+                _ => return Err(Error::MissingHeader),
+            }
+        }
+
+        // Remove values that don't change the behavior, remembering
+        // where each token was in the original stream. Comments are
+        // kept to the side.
+        let mut comments = BTreeMap::new();
+        let mut comment_positions = Vec::new();
+        let mut without_junk = Vec::new();
+
+        for (idx, (t, span)) in tokens.into_iter().enumerate() {
+            match t {
+                Token::Comment(id) => {
+                    comment_positions.push((without_junk.len(), id.to_string()));
+                },
+                Token::CommentDefinition(id, data) => {
+                    comments.insert(id.to_string(), data.to_string());
+                },
+                Token::Header |
+                Token::RegisterLabelDefinition(..) |
+                Token::LineComment(..) |
+                Token::Whitespace(..) => {},
+                t => without_junk.push((idx, t, span)),
+            }
+        }
 
         // Find all the indexes of the labels
         let label_mapping = {
             let mut map = BTreeMap::new();
 
-            for (i, t) in without_junk.iter().enumerate() {
+            for (i, &(_, ref t, _)) in without_junk.iter().enumerate() {
                 if let Token::LabelDefinition(id) = *t {
-                    map.insert(id, i);
+                    if map.insert(id, i).is_some() {
+                        return Err(Error::DuplicateLabel(id.to_string()));
+                    }
                 }
             }
 
             map
         };
 
-        let unmap = |id| label_mapping.get(id).map(|&x| x).ok_or(Error::UndefinedLabel);
+        let unmap = |id: &str, token_index, span| {
+            label_mapping.get(id).map(|&x| x).ok_or_else(|| Error::UndefinedLabel {
+                label: id.to_string(),
+                token_index: token_index,
+                span: span,
+            })
+        };
+
+        let spans = without_junk.iter().map(|&(_, _, span)| span.unwrap_or_default()).collect();
 
         // Make the instructions, resolving jump locations
-        let i = without_junk.into_iter().map(|t| {
+        let i = without_junk.into_iter().map(|(idx, t, span)| {
             let instr = match t {
                 Token::Inbox => Instruction::Inbox,
+                Token::InboxFrom(n) => Instruction::InboxFrom(n),
                 Token::Outbox => Instruction::Outbox,
                 Token::CopyFrom(r) => Instruction::CopyFrom(r),
                 Token::CopyTo(r) => Instruction::CopyTo(r),
@@ -60,21 +242,706 @@ impl Program {
                 Token::BumpDown(r) => Instruction::BumpDown(r),
                 Token::Add(r) => Instruction::Add(r),
                 Token::Sub(r) => Instruction::Sub(r),
+                #[cfg(feature = "extended-ops")]
+                Token::Zero(r) => Instruction::Zero(r),
+                #[cfg(feature = "extended-ops")]
+                Token::JumpIndirect(r) => Instruction::JumpIndirect(r),
+                #[cfg(feature = "assertions")]
+                Token::AssertAccumulator(n) => Instruction::AssertAccumulator(n),
                 Token::LabelDefinition(..) => Instruction::NoOp,
-                Token::Jump(id) => Instruction::Jump(try!(unmap(id))),
-                Token::JumpIfZero(id) => Instruction::JumpIfZero(try!(unmap(id))),
-                Token::JumpIfNegative(id) => Instruction::JumpIfNegative(try!(unmap(id))),
+                Token::Jump(id) => Instruction::Jump(try!(unmap(id, idx, span))),
+                Token::JumpIfZero(id) => Instruction::JumpIfZero(try!(unmap(id, idx, span))),
+                Token::JumpIfNegative(id) => Instruction::JumpIfNegative(try!(unmap(id, idx, span))),
                 _ => unreachable!(),
             };
             Ok(instr)
         });
 
         let instrs = try!(i.collect::<Result<_, Error<E>>>());
-        Ok(Program(instrs))
+        let program = Program {
+            instructions: instrs,
+            comments: comments,
+            comment_positions: comment_positions,
+            spans: spans,
+        };
+
+        try!(program.validate());
+        Ok(program)
+    }
+
+    #[cfg(any(test, feature = "proptest"))]
+    fn from_instructions(instructions: Vec<Instruction>) -> Program {
+        Program {
+            instructions: instructions,
+            comments: BTreeMap::new(),
+            comment_positions: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    fn validate<E>(&self) -> Result<(), Error<E>> {
+        match self.jump_targets().into_iter().find(|&t| t >= self.instructions.len()) {
+            Some(t) => Err(Error::InvalidJumpTarget(t)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instructions
+    }
+
+    // Every instruction, including the `NoOp`s that labels become
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    // Where in the source the instruction at `index` was written
+    pub fn span(&self, index: usize) -> Option<Span> {
+        self.spans.get(index).cloned()
     }
 
     pub fn stats_len(&self) -> usize {
-        self.0.iter().filter(|i| i.counts_towards_stats()).count()
+        self.instructions.iter().filter(|i| i.counts_towards_stats()).count()
+    }
+
+    // Every register named in the program. For an indirect reference
+    // that's the register holding the address, as the one it points
+    // at isn't known until the program runs.
+    pub fn registers_used(&self) -> BTreeSet<u8> {
+        self.instructions.iter().filter_map(Instruction::register).map(|r| match r {
+            Register::Direct(r) | Register::Indirect(r) => r,
+        }).collect()
+    }
+
+    // The registers that are used to hold addresses
+    pub fn indirect_registers(&self) -> BTreeSet<u8> {
+        self.instructions.iter().filter_map(Instruction::register).filter_map(|r| match r {
+            Register::Indirect(r) => Some(r),
+            Register::Direct(..) => None,
+        }).collect()
+    }
+
+    // How many times each kind of instruction appears, by mnemonic
+    pub fn instruction_histogram(&self) -> BTreeMap<&'static str, usize> {
+        self.instructions.iter().fold(BTreeMap::new(), |mut counts, i| {
+            *counts.entry(i.mnemonic()).or_insert(0) += 1;
+            counts
+        })
+    }
+
+    // Drops a `COPYFROM` that immediately follows a `COPYTO` of the
+    // same register, as the value is already in hand. A `COPYFROM`
+    // that is the target of a jump is left alone.
+    pub fn optimize(self) -> Program {
+        use super::machine::Instruction::*;
+
+        // Removing anything would move the targets held in registers
+        if self.has_indirect_jumps() {
+            return self;
+        }
+
+        let targets = self.jump_targets();
+
+        let redundant = self.instructions.windows(2).enumerate().filter_map(|(i, pair)| {
+            match (pair[0], pair[1]) {
+                (CopyTo(a), CopyFrom(b)) if a == b && !targets.contains(&(i + 1)) => Some(i + 1),
+                _ => None,
+            }
+        }).collect();
+
+        self.without(&redundant)
+    }
+
+    // Removes the `NoOp`s that labels leave behind. Jumps to a label
+    // go to the instruction that followed it instead.
+    pub fn strip_noops(self) -> Program {
+        if self.has_indirect_jumps() {
+            return self;
+        }
+
+        let noops = self.instructions.iter()
+            .enumerate()
+            .filter_map(|(i, instr)| match *instr {
+                Instruction::NoOp => Some(i),
+                _ => None,
+            })
+            .collect();
+
+        self.without(&noops)
+    }
+
+    // Removes every instruction that can't be reached from the start
+    // of the program, returning the original indexes of those removed.
+    pub fn prune_unreachable(self) -> (Program, Vec<usize>) {
+        let mut reached = BTreeSet::new();
+        let mut pending = vec![0];
+
+        while let Some(i) = pending.pop() {
+            if i >= self.instructions.len() || !reached.insert(i) {
+                continue;
+            }
+            pending.extend(self.successors(i));
+        }
+
+        let removed: BTreeSet<_> = (0..self.instructions.len())
+            .filter(|i| !reached.contains(i))
+            .collect();
+
+        let removed_indexes = removed.iter().cloned().collect();
+        (self.without(&removed), removed_indexes)
+    }
+
+    pub fn lint(&self) -> Vec<Lint> {
+        let mut lints = self.empty_accumulator_lints();
+        lints.extend(self.dead_store_lints());
+        lints.extend(self.self_loop_lints());
+        lints.sort_by_key(Lint::index);
+        lints
+    }
+
+    // Nothing ever empties the accumulator once it holds a value, so
+    // it may be empty at exactly the instructions reachable from the
+    // start without passing one that fills it.
+    fn empty_accumulator_lints(&self) -> Vec<Lint> {
+        use super::machine::Instruction::*;
+
+        let mut lints = Vec::new();
+        let mut reached = BTreeSet::new();
+        let mut pending = vec![0];
+
+        while let Some(i) = pending.pop() {
+            if i >= self.instructions.len() || !reached.insert(i) {
+                continue;
+            }
+
+            match self.instructions[i] {
+                Outbox | CopyTo(..) | Add(..) | Sub(..) |
+                JumpIfZero(..) | JumpIfNegative(..) => lints.push(Lint::EmptyAccumulator(i)),
+                #[cfg(feature = "assertions")]
+                AssertAccumulator(..) => lints.push(Lint::EmptyAccumulator(i)),
+                Inbox | InboxFrom(..) | CopyFrom(..) | BumpUp(..) | BumpDown(..) => {},
+                _ => pending.extend(self.successors(i)),
+            }
+        }
+
+        lints
+    }
+
+    // The jump may land on its own label rather than on itself, so
+    // only labels may lie between the target and the jump
+    fn self_loop_lints(&self) -> Vec<Lint> {
+        self.instructions.iter().enumerate().filter_map(|(i, instr)| match *instr {
+            Instruction::Jump(t) if t <= i && self.instructions[t..i].iter().all(|i| match *i {
+                Instruction::NoOp => true,
+                _ => false,
+            }) => Some(Lint::SelfLoop(i)),
+            _ => None,
+        }).collect()
+    }
+
+    // A register is live when some path reads it before writing it
+    // again. Reading through an address could read any register, so
+    // everything is live there.
+    fn dead_store_lints(&self) -> Vec<Lint> {
+        use super::machine::Instruction::*;
+
+        let len = self.instructions.len();
+        let everything = self.registers_used();
+        let mut live_in = vec![BTreeSet::new(); len];
+
+        let live_out = |live_in: &[BTreeSet<u8>], i: usize| {
+            self.successors(i).into_iter()
+                .filter(|&s| s < len)
+                .fold(BTreeSet::new(), |mut live, s| {
+                    live.extend(live_in[s].iter().cloned());
+                    live
+                })
+        };
+
+        let mut changed = true;
+        while changed {
+            changed = false;
+
+            for i in (0..len).rev() {
+                let mut live = live_out(&live_in, i);
+
+                match self.instructions[i] {
+                    CopyTo(Register::Direct(r)) => { live.remove(&r); },
+                    #[cfg(feature = "extended-ops")]
+                    Zero(Register::Direct(r)) => { live.remove(&r); },
+                    CopyTo(Register::Indirect(p)) => { live.insert(p); },
+                    #[cfg(feature = "extended-ops")]
+                    Zero(Register::Indirect(p)) => { live.insert(p); },
+                    ref instr => match instr.register() {
+                        Some(Register::Direct(r)) => { live.insert(r); },
+                        Some(Register::Indirect(..)) => live.extend(everything.iter().cloned()),
+                        None => {},
+                    },
+                }
+
+                if live != live_in[i] {
+                    live_in[i] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        (0..len).filter_map(|i| match self.instructions[i] {
+            CopyTo(Register::Direct(r)) if !live_out(&live_in, i).contains(&r) => Some(Lint::DeadStore(i)),
+            _ => None,
+        }).collect()
+    }
+
+    // Follows what kind of tile may be in hand and in each register
+    // along every path. `SUB` works on two letters as well as two
+    // numbers, so it is only flagged when the kinds are known to differ.
+    pub fn typecheck(&self) -> Vec<TypeWarning> {
+        use super::machine::Instruction::*;
+
+        let mut states: BTreeMap<usize, KindState> = BTreeMap::new();
+        states.insert(0, KindState::default());
+        let mut pending = vec![0];
+
+        while let Some(i) = pending.pop() {
+            if i >= self.instructions.len() {
+                continue;
+            }
+
+            let after = self.kinds_after(i, &states[&i]);
+
+            for next in self.successors(i) {
+                let merged = match states.get(&next) {
+                    Some(existing) => existing.merge(&after),
+                    None => after.clone(),
+                };
+                if states.get(&next) != Some(&merged) {
+                    states.insert(next, merged);
+                    pending.push(next);
+                }
+            }
+        }
+
+        let mut warnings = Vec::new();
+        for (&i, state) in &states {
+            let number = |found| (TileKind::Number, found);
+            let checks = match self.instructions.get(i) {
+                Some(&BumpUp(r)) | Some(&BumpDown(r)) => vec![number(state.register(r))],
+                Some(&Add(r)) => vec![number(state.accumulator), number(state.register(r))],
+                Some(&Sub(r)) => match (state.accumulator, state.register(r)) {
+                    (Some(a), Some(b)) => vec![(a, Some(b))],
+                    _ => vec![],
+                },
+                _ => vec![],
+            };
+
+            for (expected, found) in checks {
+                if found != Some(expected) {
+                    warnings.push(TypeWarning { index: i, expected: expected, found: found });
+                }
+            }
+        }
+
+        warnings
+    }
+
+    fn kinds_after(&self, i: usize, before: &KindState) -> KindState {
+        use super::machine::Instruction::*;
+
+        let mut state = before.clone();
+
+        match self.instructions[i] {
+            Inbox | InboxFrom(..) => state.accumulator = None,
+            CopyFrom(r) => state.accumulator = state.register(r),
+            CopyTo(r) => {
+                let kind = state.accumulator;
+                state.set_register(r, kind);
+            },
+            BumpUp(r) | BumpDown(r) => {
+                state.set_register(r, Some(TileKind::Number));
+                state.accumulator = Some(TileKind::Number);
+            },
+            Add(..) | Sub(..) => state.accumulator = Some(TileKind::Number),
+            #[cfg(feature = "extended-ops")]
+            Zero(r) => state.set_register(r, Some(TileKind::Number)),
+            #[cfg(feature = "assertions")]
+            AssertAccumulator(..) => state.accumulator = Some(TileKind::Number),
+            _ => {},
+        }
+
+        state
+    }
+
+    // The indexes that may execute after the instruction at `i`. This
+    // may include the index one past the end of the program.
+    fn successors(&self, i: usize) -> Vec<usize> {
+        use super::machine::Instruction::*;
+
+        match self.instructions[i] {
+            Jump(t) => vec![t],
+            JumpIfZero(t) | JumpIfNegative(t) => vec![t, i + 1],
+            // Could go anywhere
+            #[cfg(feature = "extended-ops")]
+            JumpIndirect(..) => (0..self.instructions.len()).collect(),
+            _ => vec![i + 1],
+        }
+    }
+
+    #[cfg(feature = "extended-ops")]
+    fn has_indirect_jumps(&self) -> bool {
+        self.instructions.iter().any(|i| match *i {
+            Instruction::JumpIndirect(..) => true,
+            _ => false,
+        })
+    }
+
+    #[cfg(not(feature = "extended-ops"))]
+    fn has_indirect_jumps(&self) -> bool {
+        false
+    }
+
+    // A Graphviz graph of the basic blocks, each named after the index
+    // of its first instruction. Running off the end goes to `end`. An
+    // indirect jump could land anywhere, even inside a block, but it is
+    // only drawn with an edge to the start of each block.
+    pub fn to_dot(&self) -> String {
+        use super::machine::Instruction::*;
+
+        let len = self.instructions.len();
+
+        let mut leaders = self.jump_targets();
+        leaders.insert(0);
+        for (i, instr) in self.instructions.iter().enumerate() {
+            match *instr {
+                Jump(..) | JumpIfZero(..) | JumpIfNegative(..) => { leaders.insert(i + 1); },
+                #[cfg(feature = "extended-ops")]
+                JumpIndirect(..) => { leaders.insert(i + 1); },
+                _ => {},
+            }
+        }
+        leaders.retain(|&i| i < len);
+
+        let node = |i: usize| if i < len { format!("b{}", i) } else { "end".to_string() };
+
+        let mut s = String::from("digraph program {\n    node [shape=box, fontname=\"monospace\"];\n");
+        let mut reaches_end = false;
+
+        let starts: Vec<_> = leaders.iter().cloned().collect();
+        for (n, &start) in starts.iter().enumerate() {
+            let end = starts.get(n + 1).cloned().unwrap_or(len);
+            let last = end - 1;
+
+            let text: String = self.instructions[start..end].iter()
+                .filter(|i| i.counts_towards_stats())
+                .map(|i| format!("{}\\l", i))
+                .collect();
+            s.push_str(&format!("    {} [label=\"{}: {}\"];\n", node(start), start, text));
+
+            let edges = match self.instructions[last] {
+                Jump(t) => vec![(t, "")],
+                JumpIfZero(t) | JumpIfNegative(t) => vec![(t, "taken"), (end, "not taken")],
+                #[cfg(feature = "extended-ops")]
+                JumpIndirect(..) => starts.iter().map(|&t| (t, "indirect")).collect(),
+                _ => vec![(end, "")],
+            };
+
+            for (target, label) in edges {
+                reaches_end |= target >= len;
+                if label.is_empty() {
+                    s.push_str(&format!("    {} -> {};\n", node(start), node(target)));
+                } else {
+                    s.push_str(&format!("    {} -> {} [label=\"{}\"];\n", node(start), node(target), label));
+                }
+            }
+        }
+
+        if reaches_end {
+            s.push_str("    end [shape=oval];\n");
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    fn jump_targets(&self) -> BTreeSet<usize> {
+        use super::machine::Instruction::*;
+
+        self.instructions.iter().filter_map(|i| match *i {
+            Jump(t) | JumpIfZero(t) | JumpIfNegative(t) => Some(t),
+            _ => None,
+        }).collect()
+    }
+
+    // Removes the instructions at the given indexes, adjusting jump
+    // targets and comment positions to match.
+    fn without(self, removed: &BTreeSet<usize>) -> Program {
+        use super::machine::Instruction::*;
+
+        let renumber = |i: usize| i - removed.range(..i).count();
+
+        let instructions = self.instructions.into_iter()
+            .enumerate()
+            .filter(|&(i, _)| !removed.contains(&i))
+            .map(|(_, instr)| match instr {
+                Jump(t) => Jump(renumber(t)),
+                JumpIfZero(t) => JumpIfZero(renumber(t)),
+                JumpIfNegative(t) => JumpIfNegative(renumber(t)),
+                other => other,
+            })
+            .collect();
+
+        let spans = self.spans.into_iter()
+            .enumerate()
+            .filter(|&(i, _)| !removed.contains(&i))
+            .map(|(_, span)| span)
+            .collect();
+
+        let comment_positions = self.comment_positions.into_iter()
+            .map(|(i, id)| (renumber(i), id))
+            .collect();
+
+        Program {
+            instructions: instructions,
+            comments: self.comments,
+            comment_positions: comment_positions,
+            spans: spans,
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> Result<String, ::serde_json::Error> {
+        ::serde_json::to_string_pretty(self)
+    }
+
+    #[cfg(feature = "serde")]
+    pub fn from_json(s: &str) -> Result<Program, ::serde_json::Error> {
+        ::serde_json::from_str(s)
+    }
+
+    pub fn comments(&self) -> &BTreeMap<String, String> {
+        &self.comments
+    }
+
+    pub fn comment_at(&self, index: usize) -> Option<&str> {
+        self.comment_positions.iter()
+            .find(|&&(i, _)| i == index)
+            .map(|&(_, ref id)| &id[..])
+    }
+
+    pub fn disassemble(&self) -> String {
+        self.disassemble_with_names(&BTreeMap::new())
+    }
+
+    // Registers with a name are shown by that name instead of their
+    // number, which reads better but can't be parsed back in
+    pub fn disassemble_with_names(&self, names: &BTreeMap<u8, String>) -> String {
+        use super::machine::Instruction::*;
+
+        let register_name = |r| register_name(r, names);
+
+        let labels: BTreeMap<_, _> = self.jump_targets().into_iter()
+            .enumerate()
+            .map(|(n, t)| (t, label_name(n)))
+            .collect();
+
+        let mut s = String::from("-- HUMAN RESOURCE MACHINE PROGRAM --\n\n");
+
+        let mut comments = self.comment_positions.iter().peekable();
+
+        for (i, instr) in self.instructions.iter().enumerate() {
+            if let Some(label) = labels.get(&i) {
+                s.push_str(&format!("{}:\n", label));
+            }
+
+            while let Some(&&(_, ref id)) = comments.peek().filter(|&&&(ci, _)| ci == i) {
+                s.push_str(&format!("    COMMENT  {}\n", id));
+                comments.next();
+            }
+
+            let line = match *instr {
+                Inbox => "INBOX".to_string(),
+                InboxFrom(n) => format!("INBOX    {}", n),
+                Outbox => "OUTBOX".to_string(),
+                CopyFrom(r) => format!("COPYFROM {}", register_name(r)),
+                CopyTo(r) => format!("COPYTO   {}", register_name(r)),
+                BumpUp(r) => format!("BUMPUP   {}", register_name(r)),
+                BumpDown(r) => format!("BUMPDN   {}", register_name(r)),
+                Add(r) => format!("ADD      {}", register_name(r)),
+                Sub(r) => format!("SUB      {}", register_name(r)),
+                #[cfg(feature = "extended-ops")]
+                Zero(r) => format!("ZERO     {}", register_name(r)),
+                #[cfg(feature = "extended-ops")]
+                JumpIndirect(r) => format!("JUMPI    {}", register_name(r)),
+                #[cfg(feature = "assertions")]
+                AssertAccumulator(n) => format!("ASSERTACC {}", n),
+                Jump(t) => format!("JUMP     {}", labels[&t]),
+                JumpIfZero(t) => format!("JUMPZ    {}", labels[&t]),
+                JumpIfNegative(t) => format!("JUMPN    {}", labels[&t]),
+                NoOp => continue,
+            };
+            s.push_str(&format!("    {}\n", line));
+        }
+
+        // A label may point just past the last instruction
+        if let Some(label) = labels.get(&self.instructions.len()) {
+            s.push_str(&format!("{}:\n", label));
+        }
+
+        for &(_, ref id) in comments {
+            s.push_str(&format!("    COMMENT  {}\n", id));
+        }
+
+        if !self.comments.is_empty() {
+            s.push_str("\n");
+        }
+
+        for (id, data) in &self.comments {
+            s.push_str(&format!("\nDEFINE COMMENT {}\n{};\n", id, data));
+        }
+
+        s
+    }
+}
+
+// Labels may only contain lowercase letters, so count a, b, ..., z,
+// aa, ab, ...
+fn label_name(mut n: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push((b'a' + (n % 26) as u8) as char);
+        if n < 26 { break }
+        n = n / 26 - 1;
+    }
+    name.into_iter().rev().collect()
+}
+
+fn register_name(r: Register, names: &BTreeMap<u8, String>) -> String {
+    let name = |r: u8| names.get(&r).cloned().unwrap_or_else(|| r.to_string());
+
+    match r {
+        Register::Direct(r) => name(r),
+        Register::Indirect(r) => format!("[{}]", name(r)),
+    }
+}
+
+// Programs that never finish are cut off here, so that they can still
+// be compared
+const EQUIVALENCE_STEP_LIMIT: usize = 100_000;
+
+// Whether both programs give the same output, or fail in the same way,
+// for every case
+pub fn programs_equivalent(a: &Program, b: &Program, cases: &[(Input, Registers)]) -> bool {
+    cases.iter().all(|&(ref input, ref registers)| {
+        let mut ma = Machine::new(a.clone(), input.clone(), registers.clone());
+        let mut mb = Machine::new(b.clone(), input.clone(), registers.clone());
+
+        let ra = ma.run_with_limit(EQUIVALENCE_STEP_LIMIT);
+        let rb = mb.run_with_limit(EQUIVALENCE_STEP_LIMIT);
+
+        ra == rb && ma.output() == mb.output()
+    })
+}
+
+// Generates programs of up to `max_len` instructions that only use
+// registers below `floor_size`. Every jump lands on an instruction in
+// the program and most jumps go forward, so generated programs
+// usually finish once the input runs out.
+#[cfg(feature = "proptest")]
+pub fn arbitrary_program(max_len: usize, floor_size: u8) -> ::proptest::strategy::BoxedStrategy<Program> {
+    use proptest::prelude::*;
+    use super::machine::Instruction::*;
+
+    let ops = prop::collection::vec((0..100u8, any::<usize>(), any::<bool>()), 1..max_len + 1);
+
+    ops.prop_map(move |ops| {
+        let len = ops.len();
+
+        let instructions = ops.into_iter().enumerate().map(|(i, (kind, n, indirect))| {
+            let r = if floor_size == 0 {
+                None
+            } else if indirect {
+                Some(Register::Indirect((n % floor_size as usize) as u8))
+            } else {
+                Some(Register::Direct((n % floor_size as usize) as u8))
+            };
+
+            // One in four jumps goes backwards, when it can go forwards
+            let target = if n % 4 != 0 && i + 1 < len {
+                i + 1 + n % (len - i - 1)
+            } else {
+                n % (i + 1)
+            };
+
+            match (kind, r) {
+                (0..=29, _) => Inbox,
+                (30..=44, _) => Outbox,
+                (45..=54, Some(r)) => CopyFrom(r),
+                (55..=64, Some(r)) => CopyTo(r),
+                (65..=69, Some(r)) => BumpUp(r),
+                (70..=74, Some(r)) => BumpDown(r),
+                (75..=79, Some(r)) => Add(r),
+                (80..=84, Some(r)) => Sub(r),
+                (85..=89, _) => JumpIfZero(target),
+                (90..=94, _) => JumpIfNegative(target),
+                (95..=99, _) => Jump(target),
+                _ => Inbox,
+            }
+        }).collect();
+
+        Program::from_instructions(instructions)
+    }).boxed()
+}
+
+// Builds a program from the same tokens the parser would produce, so
+// labels are resolved exactly as they are for source text.
+#[derive(Debug, Clone, Default)]
+pub struct ProgramBuilder<'a> {
+    tokens: Vec<Token<'a>>,
+}
+
+impl<'a> ProgramBuilder<'a> {
+    pub fn new() -> ProgramBuilder<'a> {
+        ProgramBuilder::default()
+    }
+
+    fn push(mut self, t: Token<'a>) -> ProgramBuilder<'a> {
+        self.tokens.push(t);
+        self
+    }
+
+    pub fn inbox(self) -> ProgramBuilder<'a> { self.push(Token::Inbox) }
+    pub fn inbox_from(self, n: u8) -> ProgramBuilder<'a> { self.push(Token::InboxFrom(n)) }
+    pub fn outbox(self) -> ProgramBuilder<'a> { self.push(Token::Outbox) }
+    pub fn copy_from(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::CopyFrom(r)) }
+    pub fn copy_to(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::CopyTo(r)) }
+    pub fn bump_up(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::BumpUp(r)) }
+    pub fn bump_down(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::BumpDown(r)) }
+    pub fn add(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::Add(r)) }
+    pub fn sub(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::Sub(r)) }
+    pub fn label(self, l: &'a str) -> ProgramBuilder<'a> { self.push(Token::LabelDefinition(l)) }
+    pub fn jump(self, l: &'a str) -> ProgramBuilder<'a> { self.push(Token::Jump(l)) }
+    pub fn jump_if_zero(self, l: &'a str) -> ProgramBuilder<'a> { self.push(Token::JumpIfZero(l)) }
+    pub fn jump_if_negative(self, l: &'a str) -> ProgramBuilder<'a> { self.push(Token::JumpIfNegative(l)) }
+
+    #[cfg(feature = "extended-ops")]
+    pub fn zero(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::Zero(r)) }
+
+    #[cfg(feature = "extended-ops")]
+    pub fn jump_indirect(self, r: Register) -> ProgramBuilder<'a> { self.push(Token::JumpIndirect(r)) }
+
+    // There's no parser involved, so there can't be a parser error
+    pub fn build(self) -> Result<Program, Error<()>> {
+        Program::compile(self.tokens.into_iter().map(Ok))
+    }
+}
+
+impl FromStr for Program {
+    type Err = Error<ParseFailure>;
+
+    fn from_str(s: &str) -> Result<Program, Self::Err> {
+        Program::compile_spanned(Parser::new(s).spanned())
     }
 }
 
@@ -83,6 +950,528 @@ impl IntoIterator for Program {
     type IntoIter = ::std::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
-        self.0.into_iter()
+        self.instructions.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::level::{self, Level};
+    use super::super::machine::{Output, Tile};
+
+    // A known-good solution for each built-in level
+    const SOLUTIONS: &'static [(usize, &'static str)] = &[
+        (1, "
+            a:
+                INBOX
+                OUTBOX
+                JUMP a
+        "),
+        (2, "
+            a:
+                INBOX
+                OUTBOX
+                JUMP a
+        "),
+        (3, "
+                COPYFROM 4
+                OUTBOX
+                COPYFROM 0
+                OUTBOX
+                COPYFROM 3
+                OUTBOX
+        "),
+        (4, "
+            a:
+                INBOX
+                COPYTO 0
+                INBOX
+                COPYTO 1
+                COPYFROM 1
+                OUTBOX
+                COPYFROM 0
+                OUTBOX
+                JUMP a
+        "),
+        (14, "
+            a:
+                INBOX
+                COPYTO 0
+                INBOX
+                COPYTO 1
+                SUB 0
+                JUMPN b
+                COPYFROM 1
+                OUTBOX
+                JUMP a
+            b:
+                COPYFROM 0
+                OUTBOX
+                JUMP a
+        "),
+        (35, LEVEL_35),
+        (36, "
+                COPYFROM 23
+                COPYTO 20
+            first:
+                INBOX
+                COPYTO [20]
+                JUMPZ firstdone
+                BUMPUP 20
+                JUMP first
+            firstdone:
+                COPYFROM 24
+                COPYTO 20
+            second:
+                INBOX
+                COPYTO [20]
+                JUMPZ seconddone
+                BUMPUP 20
+                JUMP second
+            seconddone:
+                COPYFROM 23
+                COPYTO 20
+                COPYFROM 24
+                COPYTO 21
+            compare:
+                COPYFROM [21]
+                JUMPZ outsecond
+                COPYFROM [20]
+                JUMPZ outfirst
+                SUB [21]
+                JUMPN outfirst
+                JUMPZ same
+                JUMP outsecond
+            same:
+                BUMPUP 20
+                BUMPUP 21
+                JUMP compare
+            outfirst:
+                COPYFROM 23
+                COPYTO 20
+                JUMP out
+            outsecond:
+                COPYFROM 24
+                COPYTO 20
+            out:
+                COPYFROM [20]
+                JUMPZ end
+                OUTBOX
+                BUMPUP 20
+                JUMP out
+            end:
+        "),
+        (37, "
+            next:
+                INBOX
+                COPYTO 22
+            chain:
+                COPYFROM [22]
+                OUTBOX
+                BUMPUP 22
+                COPYFROM [22]
+                COPYTO 22
+                JUMPN next
+                JUMP chain
+        "),
+        (38, "
+            next:
+                INBOX
+                COPYTO 0
+                COPYFROM 9
+                COPYTO 1
+                COPYTO 2
+                COPYFROM 0
+            hundreds:
+                SUB 11
+                JUMPN tensstart
+                COPYTO 0
+                BUMPUP 1
+                COPYFROM 0
+                JUMP hundreds
+            tensstart:
+                COPYFROM 0
+            tens:
+                SUB 10
+                JUMPN ones
+                COPYTO 0
+                BUMPUP 2
+                COPYFROM 0
+                JUMP tens
+            ones:
+                COPYFROM 1
+                JUMPZ nohundreds
+                OUTBOX
+                COPYFROM 2
+                OUTBOX
+                JUMP onesout
+            nohundreds:
+                COPYFROM 2
+                JUMPZ onesout
+                OUTBOX
+            onesout:
+                COPYFROM 0
+                OUTBOX
+                JUMP next
+        "),
+    ];
+
+    const LEVEL_35: &'static str = "
+        next:
+            INBOX
+            COPYTO 13
+            COPYFROM 14
+            COPYTO 12
+        check:
+            COPYFROM 12
+            JUMPZ new
+            BUMPDN 12
+            COPYFROM [12]
+            SUB 13
+            JUMPZ next
+            JUMP check
+        new:
+            COPYFROM 13
+            COPYTO [14]
+            BUMPUP 14
+            COPYFROM 13
+            OUTBOX
+            JUMP next
+    ";
+
+    fn solutions() -> Vec<(Level, Program)> {
+        SOLUTIONS.iter().map(|&(number, source)| {
+            let level = level::builtin(number).expect("No such level");
+            let program = source.parse().expect("Solution does not compile");
+            (level, program)
+        }).collect()
+    }
+
+    fn outputs(level: &Level, program: &Program) -> Vec<Output> {
+        level.cases().into_iter().map(|(input, _)| {
+            let mut m = Machine::new(program.clone(), input, level.registers.clone());
+            m.set_floor_size(level.floor_size);
+            m.run().expect("Solution failed");
+            m.output().clone()
+        }).collect()
+    }
+
+    #[test]
+    fn solutions_pass() {
+        for (level, program) in solutions() {
+            assert!(level.validate(&program).passed, "{} failed", level.name);
+        }
+    }
+
+    #[test]
+    fn optimize_keeps_the_output_of_every_level() {
+        let mut removed = 0;
+
+        for (level, program) in solutions() {
+            let optimized = program.clone().optimize();
+            removed += program.len() - optimized.len();
+
+            assert_eq!(outputs(&level, &program), outputs(&level, &optimized), "{}", level.name);
+        }
+
+        assert!(removed > 0);
+    }
+
+    #[test]
+    fn labels_are_free() {
+        let program: Program = "INBOX\nlabel:\nOUTBOX\nJUMP label".parse().unwrap();
+        assert_eq!(program.len(), 4);
+        assert_eq!(program.stats_len(), 3);
+    }
+
+    #[test]
+    fn line_comments_are_ignored() {
+        let program: Program = "a: -- start\nINBOX -- take\nOUTBOX\nJUMP a -- again".parse().unwrap();
+        let plain: Program = "a:\nINBOX\nOUTBOX\nJUMP a".parse().unwrap();
+        assert_eq!(program.disassemble(), plain.disassemble());
+        assert_eq!(program.len(), 4);
+    }
+
+    fn level_cases(level: &Level) -> Vec<(Input, Registers)> {
+        level.cases().into_iter().map(|(input, _)| (input, level.registers.clone())).collect()
+    }
+
+    #[test]
+    fn optimize_and_prune_unreachable_preserve_equivalence() {
+        for (level, program) in solutions() {
+            let cases = level_cases(&level);
+            let (pruned, _) = program.clone().prune_unreachable();
+
+            assert!(programs_equivalent(&program, &program.clone().optimize(), &cases), "{}", level.name);
+            assert!(programs_equivalent(&program, &pruned, &cases), "{}", level.name);
+        }
+    }
+
+    #[test]
+    fn prune_unreachable_preserves_equivalence_when_it_removes_code() {
+        let program: Program = "a:\nINBOX\nOUTBOX\nJUMP a\nCOPYFROM 0\nOUTBOX".parse().unwrap();
+        let (pruned, removed) = program.clone().prune_unreachable();
+
+        assert_eq!(removed, [4, 5]);
+        assert!(programs_equivalent(&program, &pruned, &level_cases(&level::level_1())));
+    }
+
+    #[test]
+    fn different_programs_are_not_equivalent() {
+        let cases = level_cases(&level::level_1());
+        let copy: Program = "a:\nINBOX\nOUTBOX\nJUMP a".parse().unwrap();
+        let once: Program = "INBOX\nOUTBOX".parse().unwrap();
+        let fails: Program = "a:\nINBOX\nOUTBOX\nOUTBOX\nJUMP a".parse().unwrap();
+
+        assert!(!programs_equivalent(&copy, &once, &cases));
+        assert!(!programs_equivalent(&copy, &fails, &cases));
+        assert!(programs_equivalent(&copy, &copy, &cases));
+    }
+
+    #[test]
+    fn strip_noops_keeps_the_output_of_every_level() {
+        for (level, program) in solutions() {
+            let stripped = program.clone().strip_noops();
+
+            assert_eq!(stripped.len(), program.stats_len(), "{}", level.name);
+            assert_eq!(outputs(&level, &program), outputs(&level, &stripped), "{}", level.name);
+        }
+    }
+
+    #[test]
+    fn strip_noops_with_a_label_at_the_end() {
+        let program: Program = "INBOX\nJUMPZ end\nOUTBOX\nend:".parse().unwrap();
+        let stripped = program.strip_noops();
+
+        assert_eq!(format!("{:?}", stripped.instructions()), "[Inbox, JumpIfZero(3), Outbox]");
+
+        for &(n, ref expected) in &[(0, vec![]), (5, vec![Tile::num(5)])] {
+            let mut m = Machine::new(stripped.clone(), vec![Tile::num(n)], Registers::new());
+            assert_eq!(m.run(), Ok(()));
+            assert_eq!(m.output(), expected);
+        }
+    }
+
+    #[test]
+    fn registers_used_with_direct_and_indirect_references() {
+        let program: Program = "INBOX\nCOPYTO 3\nCOPYTO [7]\nCOPYFROM 3\nADD [5]\nBUMPUP 7\nSUB 1".parse().unwrap();
+
+        assert_eq!(program.registers_used().into_iter().collect::<Vec<_>>(), [1, 3, 5, 7]);
+        assert_eq!(program.indirect_registers().into_iter().collect::<Vec<_>>(), [5, 7]);
+    }
+
+    #[test]
+    fn registers_used_without_registers() {
+        let program: Program = "a:\nINBOX\nOUTBOX\nJUMP a".parse().unwrap();
+
+        assert!(program.registers_used().is_empty());
+        assert!(program.indirect_registers().is_empty());
+    }
+
+    fn lints_where<F>(source: &str, wanted: F) -> Vec<Lint>
+        where F: Fn(&Lint) -> bool
+    {
+        let program: Program = source.parse().unwrap();
+        program.lint().into_iter().filter(|l| wanted(l)).collect()
+    }
+
+    fn self_loops(source: &str) -> Vec<Lint> {
+        lints_where(source, |l| match *l { Lint::SelfLoop(..) => true, _ => false })
+    }
+
+    fn dead_stores(source: &str) -> Vec<Lint> {
+        lints_where(source, |l| match *l { Lint::DeadStore(..) => true, _ => false })
+    }
+
+    fn empty_accumulators(source: &str) -> Vec<Lint> {
+        lints_where(source, |l| match *l { Lint::EmptyAccumulator(..) => true, _ => false })
+    }
+
+    #[test]
+    fn jump_to_its_own_label_is_a_self_loop() {
+        assert_eq!(self_loops("label:\nJUMP label"), [Lint::SelfLoop(1)]);
+        assert_eq!(self_loops("INBOX\na:\nb:\nJUMP a"), [Lint::SelfLoop(3)]);
+    }
+
+    #[test]
+    fn jump_over_an_instruction_is_not_a_self_loop() {
+        assert_eq!(self_loops("label:\nINBOX\nJUMP label"), []);
+        assert_eq!(self_loops("JUMP label\nlabel:\nINBOX"), []);
+    }
+
+    #[test]
+    fn store_replaced_before_it_is_read_is_dead() {
+        assert_eq!(dead_stores("a:\nINBOX\nCOPYTO 0\nINBOX\nCOPYTO 0\nADD 0\nOUTBOX\nJUMP a"), [Lint::DeadStore(2)]);
+    }
+
+    #[test]
+    fn store_that_is_read_is_not_dead() {
+        assert_eq!(dead_stores("a:\nINBOX\nCOPYTO 0\nINBOX\nADD 0\nOUTBOX\nJUMP a"), []);
+        // Reading through an address could read any register
+        assert_eq!(dead_stores("a:\nINBOX\nCOPYTO 0\nINBOX\nCOPYTO 1\nCOPYFROM [1]\nOUTBOX\nJUMP a"), []);
+    }
+
+    #[test]
+    fn accumulator_read_before_it_is_filled_is_empty() {
+        assert_eq!(empty_accumulators("OUTBOX"), [Lint::EmptyAccumulator(0)]);
+        assert_eq!(empty_accumulators("a:\nJUMPZ a"), [Lint::EmptyAccumulator(1)]);
+    }
+
+    #[test]
+    fn accumulator_filled_on_every_path_is_not_empty() {
+        assert_eq!(empty_accumulators("a:\nINBOX\nOUTBOX\nJUMP a"), []);
+        assert_eq!(empty_accumulators("INBOX\na:\nJUMPZ a\nOUTBOX"), []);
+    }
+
+    #[test]
+    fn bump_of_an_unknown_tile_is_a_type_warning() {
+        let program: Program = "INBOX\nCOPYTO 0\nBUMPUP 0".parse().unwrap();
+        assert_eq!(program.typecheck(), [TypeWarning { index: 2, expected: TileKind::Number, found: None }]);
+    }
+
+    #[test]
+    fn arithmetic_on_known_numbers_is_not_a_type_warning() {
+        let program: Program = "INBOX\nCOPYTO 0\nSUB 0\nCOPYTO 1\nBUMPUP 1\nADD 1".parse().unwrap();
+        assert_eq!(program.typecheck(), []);
+    }
+
+    #[test]
+    fn jump_to_a_label_at_the_end() {
+        let program: Program = "a:\nINBOX\nJUMPZ b\nJUMP a\nb:".parse().unwrap();
+        assert_eq!(program.len(), 5);
+        assert!(program.validate::<()>().is_ok());
+    }
+
+    #[test]
+    fn jump_past_the_end_is_invalid() {
+        let program = Program::from_instructions(vec![Instruction::Inbox, Instruction::Jump(2)]);
+        match program.validate::<()>() {
+            Err(Error::InvalidJumpTarget(2)) => {},
+            other => panic!("Expected an invalid jump target, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undefined_label_points_at_the_jump() {
+        let source = "INBOX\nJUMPZ missing\n";
+        match source.parse::<Program>() {
+            Err(Error::UndefinedLabel { ref label, span: Some(span), .. }) => {
+                assert_eq!(label, "missing");
+                assert_eq!(&source[span.start..span.end], "JUMPZ missing");
+            },
+            other => panic!("Expected an undefined label, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn label_defined_twice() {
+        match "a:\nINBOX\na:\nJUMP a".parse::<Program>() {
+            Err(Error::DuplicateLabel(ref label)) if label == "a" => {},
+            other => panic!("Expected a duplicate label, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn header_is_required_by_the_options() {
+        let options = Options { require_header: true };
+
+        match Program::compile_with_options(Parser::new("INBOX\nOUTBOX"), options) {
+            Err(Error::MissingHeader) => {},
+            other => panic!("Expected a missing header, got {:?}", other),
+        }
+
+        let source = "-- HUMAN RESOURCE MACHINE PROGRAM --\n\nINBOX\nOUTBOX";
+        let program = Program::compile_with_options(Parser::new(source), options).expect("Header was not found");
+        assert_eq!(program.len(), 2);
+    }
+
+    #[test]
+    fn built_program_matches_the_source() {
+        let built = ProgramBuilder::new()
+            .label("a")
+            .inbox()
+            .jump_if_zero("a")
+            .outbox()
+            .jump("a")
+            .build()
+            .expect("Built program does not compile");
+        let parsed: Program = "a:\nINBOX\nJUMPZ a\nOUTBOX\nJUMP a".parse().unwrap();
+
+        assert_eq!(built.disassemble(), parsed.disassemble());
+
+        match ProgramBuilder::new().jump("missing").build() {
+            Err(Error::UndefinedLabel { ref label, span: None, .. }) if label == "missing" => {},
+            other => panic!("Expected an undefined label, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dot_of_a_conditional_jump() {
+        let program: Program = "a:\nINBOX\nJUMPZ b\nOUTBOX\nJUMP a\nb:".parse().unwrap();
+        assert_eq!(program.to_dot(), concat!(
+            "digraph program {\n",
+            "    node [shape=box, fontname=\"monospace\"];\n",
+            "    b0 [label=\"0: INBOX\\lJUMPZ 5\\l\"];\n",
+            "    b0 -> b5 [label=\"taken\"];\n",
+            "    b0 -> b3 [label=\"not taken\"];\n",
+            "    b3 [label=\"3: OUTBOX\\lJUMP 0\\l\"];\n",
+            "    b3 -> b0;\n",
+            "    b5 [label=\"5: \"];\n",
+            "    b5 -> end;\n",
+            "    end [shape=oval];\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn disassembly_parses_back_to_the_same_program() {
+        for (level, program) in solutions() {
+            let source = program.disassemble();
+            let reparsed: Program = source.parse().expect("Disassembly does not compile");
+
+            assert_eq!(reparsed.disassemble(), source);
+            assert_eq!(outputs(&level, &reparsed), outputs(&level, &program), "{}", level.name);
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_round_trip() {
+        for (_, program) in solutions() {
+            let json = program.to_json().expect("Could not serialize");
+            let reloaded = Program::from_json(&json).expect("Could not deserialize");
+
+            assert_eq!(format!("{:?}", reloaded), format!("{:?}", program));
+        }
+    }
+
+    #[cfg(feature = "proptest")]
+    mod properties {
+        use proptest::prelude::*;
+
+        use super::super::arbitrary_program;
+        use super::super::super::machine::{Machine, Tile};
+
+        fn arbitrary_tile() -> BoxedStrategy<Tile> {
+            prop_oneof![
+                (-999i16..1000).prop_map(Tile::num),
+                (b'a'..b'z' + 1).prop_map(|c| Tile::Letter(c as char)),
+            ].boxed()
+        }
+
+        proptest! {
+            #[test]
+            fn machine_never_panics(
+                program in arbitrary_program(30, 5),
+                input in prop::collection::vec(arbitrary_tile(), 0..20),
+                registers in prop::collection::btree_map(0..5u8, arbitrary_tile(), 0..5),
+            ) {
+                let mut m = Machine::new(program, input, registers);
+                m.set_floor_size(5);
+                // Any result is fine, as long as there is one
+                let _ = m.run_with_limit(10_000);
+            }
+        }
     }
 }
@@ -1,8 +1,354 @@
 use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use rustc_serialize::json::{self, Json};
+
+use super::compiler::Program;
+use super::machine::{self, GradeReport, Input, Machine, Output, Registers, RunConstraints, Stats, Tile, TileKind, grade};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Json(json::BuilderError),
+    MissingField(&'static str),
+    InvalidField(&'static str),
+    InvalidTile,
+    InvalidRegister,
+    NegativeRegister,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "could not read the level: {}", e),
+            Error::Json(ref e) => write!(f, "the level is not valid JSON: {}", e),
+            Error::MissingField(name) => write!(f, "the level has no {} field", name),
+            Error::InvalidField(name) => write!(f, "the level's {} field is not valid", name),
+            Error::InvalidTile => "the level contains an invalid tile".fmt(f),
+            Error::InvalidRegister => "the level contains an invalid register number".fmt(f),
+            Error::NegativeRegister => "the level contains a negative register number".fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Json(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<json::BuilderError> for Error {
+    fn from(e: json::BuilderError) -> Error {
+        Error::Json(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Level {
+    pub input: Input,
+    pub registers: Registers,
+    pub expected_output: Output,
+    pub additional_cases: Vec<(Input, Output)>,
+    pub name: String,
+    pub floor_size: usize,
+    // Only the listed registers are constrained
+    pub tile_kinds: BTreeMap<u8, TileKind>,
+}
+
+// Correct programs never come close to this, so anything beyond it
+// is a runaway loop.
+const OUTPUT_SAFETY_FACTOR: usize = 10;
+
+// Far more than any correct program takes on a level, so that one
+// which never finishes fails instead of running forever
+const STEP_LIMIT: usize = 100_000;
+
+// How a program did on a level. Everything but `passed` describes a
+// single case: the first one that failed, or the level's own input
+// when they all pass.
+#[derive(Debug, Clone)]
+pub struct ValidationResult {
+    pub passed: bool,
+    // 0 is the level's own input, then the additional cases follow
+    pub case: usize,
+    pub steps: usize,
+    pub stats_len: usize,
+    pub stats: Stats,
+    pub error: Option<machine::Error>,
+    pub report: GradeReport,
+    // Where the machine stopped and what it held, to explain an error
+    pub pc: usize,
+    pub accumulator: Option<Tile>,
+}
+
+impl Level {
+    pub fn max_output(&self) -> usize {
+        let longest = self.cases().iter().map(|&(_, ref output)| output.len()).max().unwrap_or(0);
+        (longest * OUTPUT_SAFETY_FACTOR).max(OUTPUT_SAFETY_FACTOR)
+    }
+
+    pub fn constraints(&self) -> RunConstraints {
+        RunConstraints {
+            max_steps: Some(STEP_LIMIT),
+            floor_size: Some(self.floor_size),
+            max_output: Some(self.max_output()),
+        }
+    }
+
+    // Runs every case of the level, stopping at the first that fails
+    pub fn validate(&self, program: &Program) -> ValidationResult {
+        let mut first = None;
+
+        for (case, (input, expected)) in self.cases().into_iter().enumerate() {
+            let mut m = Machine::new(program.clone(), input, self.registers.clone());
+            m.set_tile_kinds(self.tile_kinds.clone());
+            let error = m.run_constrained(self.constraints()).err();
+            let report = grade(&expected, m.output());
+
+            let result = ValidationResult {
+                passed: error.is_none() && report.passed,
+                case: case,
+                steps: m.steps(),
+                stats_len: program.stats_len(),
+                stats: m.stats(),
+                error: error,
+                report: report,
+                pc: m.pc(),
+                accumulator: m.accumulator(),
+            };
+
+            if !result.passed {
+                return result;
+            }
+            first = first.or(Some(result));
+        }
+
+        first.expect("A level always has at least one case")
+    }
+
+    // A built-in level with a fresh input generated from `seed`, and
+    // the output that goes with it. Levels whose inputs don't vary
+    // give `None`.
+    pub fn randomized(number: usize, seed: u64) -> Option<Level> {
+        let mut rng = Rng(seed);
+        let mut level = match builtin(number) {
+            Some(level) => level,
+            None => return None,
+        };
+
+        let input = match number {
+            1 => rng.numbers(3, 6),
+            2 => rng.letters(8, 12, 26),
+            4 => rng.tiles(2, 5, 2),
+            14 => {
+                let n = rng.range(2, 5) * 2;
+                (0..n).map(|_| Tile::num(rng.number())).collect()
+            },
+            35 => rng.letters(8, 12, 5),
+            _ => return None,
+        };
+
+        level.expected_output = expected_output(number, &input, &level.registers)
+            .expect("Randomized level has no reference solution");
+        level.input = input;
+        Some(level)
+    }
+
+    pub fn cases(&self) -> Vec<(Input, Output)> {
+        let mut cases = vec![(self.input.clone(), self.expected_output.clone())];
+        cases.extend(self.additional_cases.iter().cloned());
+        cases
+    }
+
+    // A level file is a JSON object like
+    //
+    // {
+    //   "name": "Mail Room",
+    //   "floor_size": 0,
+    //   "input": [1, "a", -3],
+    //   "registers": { "14": 0 },
+    //   "expected_output": [1, "a", -3],
+    //   "additional_cases": [
+    //     { "input": [2], "expected_output": [2] }
+    //   ]
+    // }
+    //
+    // Integers are number tiles. In a list of tiles each character of a
+    // string is a letter tile, so zero-terminated words can be written
+    // as `["aab", 0, "aaa", 0]`, while a register holds a single-character
+    // string. `registers` and `additional_cases` may be omitted.
+    pub fn from_json_file<P>(path: P) -> Result<Level, Error>
+        where P: AsRef<Path>
+    {
+        let mut f = try!(File::open(path));
+        Level::from_json_reader(&mut f)
+    }
 
-use super::machine::{Input, Output, Registers, Tile};
+    pub fn from_json_reader<R>(r: &mut R) -> Result<Level, Error>
+        where R: Read
+    {
+        let json = try!(Json::from_reader(r));
+        Level::from_json(&json)
+    }
+
+    pub fn from_json(json: &Json) -> Result<Level, Error> {
+        let name = try!(field(json, "name"));
+        let name = try!(name.as_string().ok_or(Error::InvalidField("name")));
+
+        let floor_size = try!(field(json, "floor_size"));
+        let floor_size = try!(floor_size.as_u64().ok_or(Error::InvalidField("floor_size")));
+
+        let input = try!(tiles_from_json(try!(field(json, "input"))));
+        let expected_output = try!(tiles_from_json(try!(field(json, "expected_output"))));
+
+        let registers = match json.find("registers") {
+            Some(r) => try!(registers_from_json(r)),
+            None => BTreeMap::new(),
+        };
+
+        let tile_kinds = match json.find("tile_kinds") {
+            Some(k) => try!(tile_kinds_from_json(k)),
+            None => BTreeMap::new(),
+        };
+
+        let additional_cases = match json.find("additional_cases") {
+            Some(cases) => {
+                let cases = try!(cases.as_array().ok_or(Error::InvalidField("additional_cases")));
+                let mut parsed = Vec::new();
+                for case in cases {
+                    let input = try!(tiles_from_json(try!(field(case, "input"))));
+                    let output = try!(tiles_from_json(try!(field(case, "expected_output"))));
+                    parsed.push((input, output));
+                }
+                parsed
+            },
+            None => Vec::new(),
+        };
+
+        Ok(Level {
+            input: input,
+            registers: registers,
+            expected_output: expected_output,
+            additional_cases: additional_cases,
+            name: name.to_string(),
+            floor_size: floor_size as usize,
+            tile_kinds: tile_kinds,
+        })
+    }
+}
+
+fn field<'a>(json: &'a Json, name: &'static str) -> Result<&'a Json, Error> {
+    json.find(name).ok_or(Error::MissingField(name))
+}
+
+// A string in a list of tiles is one letter tile for each character,
+// so zero-terminated words can be written as `["aab", 0, "aaa", 0]`
+fn tiles_from_json(json: &Json) -> Result<Vec<Tile>, Error> {
+    let values = try!(json.as_array().ok_or(Error::InvalidTile));
+
+    let mut tiles = Vec::new();
+    for value in values {
+        match *value {
+            Json::String(ref s) => tiles.extend(s.chars().map(Tile::Letter)),
+            ref value => tiles.push(try!(tile_from_json(value))),
+        }
+    }
+
+    Ok(tiles)
+}
+
+fn tile_from_json(json: &Json) -> Result<Tile, Error> {
+    match *json {
+        Json::I64(n) if n >= i16::MIN as i64 && n <= i16::MAX as i64 => {
+            Tile::try_num(n as i16).map_err(|_| Error::InvalidTile)
+        },
+        Json::U64(n) if n <= i16::MAX as u64 => {
+            Tile::try_num(n as i16).map_err(|_| Error::InvalidTile)
+        },
+        Json::String(ref s) => {
+            let mut chars = s.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Ok(Tile::Letter(c)),
+                _ => Err(Error::InvalidTile),
+            }
+        },
+        _ => Err(Error::InvalidTile),
+    }
+}
+
+fn registers_from_json(json: &Json) -> Result<Registers, Error> {
+    let values = try!(json.as_object().ok_or(Error::InvalidField("registers")));
+
+    let mut registers = BTreeMap::new();
+    for (idx, tile) in values {
+        registers.insert(try!(register_from_json(idx)), try!(tile_from_json(tile)));
+    }
 
-pub type Level = (Input, Registers, Output);
+    Ok(registers)
+}
+
+fn register_from_json(idx: &str) -> Result<u8, Error> {
+    let idx: i64 = try!(idx.parse().map_err(|_| Error::InvalidRegister));
+    if idx < 0 {
+        return Err(Error::NegativeRegister);
+    }
+    if idx > u8::MAX as i64 {
+        return Err(Error::InvalidRegister);
+    }
+    Ok(idx as u8)
+}
+
+// Written as `{"0": "number", "1": "letter"}`
+fn tile_kinds_from_json(json: &Json) -> Result<BTreeMap<u8, TileKind>, Error> {
+    let values = try!(json.as_object().ok_or(Error::InvalidField("tile_kinds")));
+
+    let mut kinds = BTreeMap::new();
+    for (idx, kind) in values {
+        let kind = match kind.as_string() {
+            Some("number") => TileKind::Number,
+            Some("letter") => TileKind::Letter,
+            _ => return Err(Error::InvalidField("tile_kinds")),
+        };
+        kinds.insert(try!(register_from_json(idx)), kind);
+    }
+
+    Ok(kinds)
+}
+
+const BUILTIN: &'static [(usize, fn() -> Level)] = &[
+    (1, level_1),
+    (2, level_2),
+    (3, level_3),
+    (4, level_4),
+    (14, level_14),
+    (35, level_35),
+    (36, level_36),
+    (37, level_37),
+    (38, level_38),
+];
+
+pub fn builtin(number: usize) -> Option<Level> {
+    BUILTIN.iter().find(|&&(n, _)| n == number).map(|&(_, level)| level())
+}
+
+// In order of level number
+pub fn builtin_levels() -> Vec<(usize, Level)> {
+    BUILTIN.iter().map(|&(n, level)| (n, level())).collect()
+}
 
 // Copy inbox to outbox
 pub fn level_1() -> Level {
@@ -12,7 +358,15 @@ pub fn level_1() -> Level {
 
     let output = input.clone();
 
-    (input, registers, output)
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![same(from_numbers(&[4, -2, 9]))],
+        name: "Mail Room".to_string(),
+        floor_size: 0,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // Copy long inbox to outbox
@@ -23,7 +377,15 @@ pub fn level_2() -> Level {
 
     let output = input.clone();
 
-    (input, registers, output)
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![same(from_string("automate"))],
+        name: "Busy Mail Room".to_string(),
+        floor_size: 0,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // Copy from tiles to outbox
@@ -37,30 +399,72 @@ pub fn level_3() -> Level {
 
     let output = from_string("bug");
 
-    (input, registers, output)
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![(from_numbers(&[3, 7, 1, 2]), from_string("bug"))],
+        name: "Copy Floor".to_string(),
+        floor_size: 6,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // Swap pairs from the input
 pub fn level_4() -> Level {
-    let input = parse_mixed("6,4,-1,7,ih");
+    let input = parse_mixed("6,4,-1,7,ih").unwrap();
 
     let registers = BTreeMap::new();
 
-    let output = parse_mixed("4,6,7,-1,hi");
+    let output = parse_mixed("4,6,7,-1,hi").unwrap();
+
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![(parse_mixed("3,-8,ab,0,9").unwrap(), parse_mixed("-8,3,ba,9,0").unwrap())],
+        name: "Scrambler Handler".to_string(),
+        floor_size: 3,
+        tile_kinds: BTreeMap::new(),
+    }
+}
+
+// Output the larger of each pair
+pub fn level_14() -> Level {
+    let input = from_numbers(&[3, 9, -6, -2, 5, 5, 8, -3]);
 
-    (input, registers, output)
+    let registers = BTreeMap::new();
+
+    let output = from_numbers(&[9, -2, 5, 8]);
+
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![(from_numbers(&[0, -1, -7, 4, 99, -99]), from_numbers(&[0, 4, 99]))],
+        name: "Maximization Room".to_string(),
+        floor_size: 3,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // Copy inbox to outbox, losing duplicates
 pub fn level_35() -> Level {
     let input = from_string("eabedebaeb");
 
-    let mut registers = BTreeMap::new();
-    registers.insert(14, Tile::num(0));
+    let registers = registers_from(&[(14, Tile::num(0))]);
 
     let output = from_string("eabd");
 
-    (input, registers, output)
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![(from_string("ccbac"), from_string("cba"))],
+        name: "Duplicate Removal".to_string(),
+        floor_size: 15,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // Given two zero-terminated words, output the word that is first in
@@ -70,13 +474,24 @@ pub fn level_36() -> Level {
     append_zero_terminated_string(&mut input, "aab");
     append_zero_terminated_string(&mut input, "aaa");
 
-    let mut registers = BTreeMap::new();
-    registers.insert(23, Tile::num(0));
-    registers.insert(24, Tile::num(10));
+    let registers = registers_from(&[(23, Tile::num(0)), (24, Tile::num(10))]);
 
     let output = from_string("aaa");
 
-    (input, registers, output)
+    let mut other_input = Vec::new();
+    append_zero_terminated_string(&mut other_input, "cat");
+    append_zero_terminated_string(&mut other_input, "car");
+    let additional_cases = vec![(other_input, from_string("car"))];
+
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: additional_cases,
+        name: "Alphabetizer".to_string(),
+        floor_size: 25,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // There are pairs of letters and next pointers in the registers,
@@ -85,50 +500,213 @@ pub fn level_36() -> Level {
 pub fn level_37() -> Level {
     let input = from_numbers(&[0, 23]);
 
-    let mut registers = BTreeMap::new();
-    let z = [
-        (0, 'e', 13),
-        (3, 'c', 23),
-        (10, 'p', 20),
-        (13, 's', 3),
-        (20, 'e', -1),
-        (23, 'a', 10),
-    ];
-    for &(idx, c, v) in &z {
-        registers.insert(idx, Tile::Letter(c));
-        registers.insert(idx + 1, Tile::num(v));
-    }
+    // Each letter is followed by the register of the next one
+    let registers = registers_from(&[
+        (0, Tile::Letter('e')), (1, Tile::num(13)),
+        (3, Tile::Letter('c')), (4, Tile::num(23)),
+        (10, Tile::Letter('p')), (11, Tile::num(20)),
+        (13, Tile::Letter('s')), (14, Tile::num(3)),
+        (20, Tile::Letter('e')), (21, Tile::num(-1)),
+        (23, Tile::Letter('a')), (24, Tile::num(10)),
+    ]);
 
     let output = from_string("escapeape");
 
-    (input, registers, output)
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![(from_numbers(&[13, 20]), from_string("scapee"))],
+        name: "Scavenger Chain".to_string(),
+        floor_size: 25,
+        tile_kinds: BTreeMap::new(),
+    }
 }
 
 // Given numbers, output the digits of the numbers
 pub fn level_38() -> Level {
     let input = from_numbers(&[33, 505, 7, 979]);
 
-    let mut registers = BTreeMap::new();
-    registers.insert(9, Tile::num(0));
-    registers.insert(10, Tile::num(10));
-    registers.insert(11, Tile::num(100));
+    let registers = registers_from(&[(9, Tile::num(0)), (10, Tile::num(10)), (11, Tile::num(100))]);
 
     let output = from_numbers(&[3, 3, 5, 0, 5, 7, 9, 7, 9]);
 
-    (input, registers, output)
+    Level {
+        input: input,
+        registers: registers,
+        expected_output: output,
+        additional_cases: vec![(from_numbers(&[42, 100, 9]), from_numbers(&[4, 2, 1, 0, 0, 9]))],
+        name: "Digit Exploder".to_string(),
+        floor_size: 12,
+        tile_kinds: BTreeMap::new(),
+    }
+}
+
+// What a correct program for each built-in level outputs, for any
+// valid input to it. Input the level can't have, such as a letter
+// where it wants a number or a chain that never ends, gives `None`.
+pub fn expected_output(level: usize, input: &Input, registers: &Registers) -> Option<Output> {
+    let register = |i: i16| {
+        if i < 0 || i > u8::MAX as i16 { None } else { registers.get(&(i as u8)).cloned() }
+    };
+    let numbers = || input.iter().map(Tile::as_number).collect::<Option<Vec<_>>>();
+
+    let output = match level {
+        1 | 2 => input.clone(),
+        // The inbox is ignored, the letters spell "bug"
+        3 => return [4, 0, 3].iter().map(|&i| register(i)).collect(),
+        4 => input.chunks(2).flat_map(|pair| pair.iter().rev().cloned()).collect(),
+        14 => {
+            let numbers = match numbers() {
+                Some(numbers) => numbers,
+                None => return None,
+            };
+            if numbers.len() % 2 != 0 {
+                return None;
+            }
+            numbers.chunks(2).map(|pair| Tile::num(pair[0].max(pair[1]))).collect()
+        },
+        35 => {
+            let mut output = Vec::new();
+            for &t in input {
+                if !output.contains(&t) { output.push(t) }
+            }
+            output
+        },
+        36 => {
+            let mut words = input.split(|t| t.as_number() == Some(0));
+            let first = words.next().unwrap_or(&[]);
+            let second = words.next().unwrap_or(&[]);
+            let letters = |w: &[Tile]| w.iter().map(|t| t.to_string()).collect::<String>();
+            if letters(second) < letters(first) { second.to_vec() } else { first.to_vec() }
+        },
+        37 => {
+            let mut output = Vec::new();
+            for t in input {
+                let mut i = match t.as_number() {
+                    Some(i) => i,
+                    None => return None,
+                };
+                // Each link takes two registers, so a chain with more
+                // links than there are registers must go round in a loop
+                let mut links = 0;
+                while i != -1 {
+                    if links > registers.len() {
+                        return None;
+                    }
+                    links += 1;
+
+                    match (register(i), register(i + 1).and_then(|t| t.as_number())) {
+                        (Some(letter), Some(next)) => {
+                            output.push(letter);
+                            i = next;
+                        },
+                        _ => return None,
+                    }
+                }
+            }
+            output
+        },
+        38 => {
+            let numbers = match numbers() {
+                Some(numbers) => numbers,
+                None => return None,
+            };
+            if numbers.iter().any(|&n| n < 0) {
+                return None;
+            }
+            let mut output = Vec::new();
+            for n in numbers {
+                let digits = n.to_string();
+                output.extend(digits.chars().map(|c| Tile::num(c.to_digit(10).unwrap() as i16)));
+            }
+            output
+        },
+        _ => return None,
+    };
+
+    Some(output)
+}
+
+// SplitMix64, which is small and happy with any seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // Inclusive of both ends
+    fn range(&mut self, low: usize, high: usize) -> usize {
+        low + (self.next() % (high - low + 1) as u64) as usize
+    }
+
+    // Kept small enough that adding or subtracting two won't overflow
+    fn number(&mut self) -> i16 {
+        self.range(0, 198) as i16 - 99
+    }
+
+    fn numbers(&mut self, min_len: usize, max_len: usize) -> Input {
+        let n = self.range(min_len, max_len);
+        (0..n).map(|_| Tile::num(self.number())).collect()
+    }
+
+    // Drawn from the first `alphabet` letters
+    fn letters(&mut self, min_len: usize, max_len: usize, alphabet: usize) -> Input {
+        let n = self.range(min_len, max_len);
+        (0..n).map(|_| Tile::Letter((b'a' + self.range(0, alphabet - 1) as u8) as char)).collect()
+    }
+
+    // A mix of letters and numbers, in whole groups of `group` tiles
+    fn tiles(&mut self, min_groups: usize, max_groups: usize, group: usize) -> Input {
+        let n = self.range(min_groups, max_groups) * group;
+        (0..n).map(|_| {
+            if self.next() % 2 == 0 {
+                Tile::num(self.number())
+            } else {
+                Tile::Letter((b'a' + self.range(0, 25) as u8) as char)
+            }
+        }).collect()
+    }
 }
 
-fn parse_mixed(s: &str) -> Input {
+fn same(input: Input) -> (Input, Output) {
+    (input.clone(), input)
+}
+
+// Comma-separated parts that are numbers if they parse as one, and
+// letters otherwise. `#n` is always the number `n`, and `'xy'` is
+// always the letters `x` and `y`, even if they look like digits. A
+// malformed escape is an invalid tile.
+fn parse_mixed(s: &str) -> Result<Input, Error> {
     let mut input = Vec::new();
 
     for part in s.split(",") {
-        match part.parse() {
-            Ok(n) => input.push(Tile::num(n)),
-            Err(..) => append_string(&mut input, part),
+        if part.starts_with("#") {
+            let n = try!(part[1..].parse().map_err(|_| Error::InvalidTile));
+            input.push(Tile::num(n));
+        } else if part.starts_with("'") {
+            if part.len() < 2 || !part.ends_with("'") {
+                return Err(Error::InvalidTile);
+            }
+            append_string(&mut input, &part[1..part.len() - 1]);
+        } else {
+            match part.parse() {
+                Ok(n) => input.push(Tile::num(n)),
+                Err(..) => append_string(&mut input, part),
+            }
         }
     }
 
-    input
+    Ok(input)
+}
+
+pub fn registers_from(tiles: &[(u8, Tile)]) -> Registers {
+    tiles.iter().cloned().collect()
 }
 
 fn from_numbers(n: &[i16]) -> Input {
@@ -155,3 +733,93 @@ fn append_zero_terminated_string(input: &mut Input, s: &str) {
     append_string(input, s);
     input.push(Tile::num(0));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tiles(json: &str) -> Result<Vec<Tile>, Error> {
+        tiles_from_json(&Json::from_str(json).expect("Invalid JSON"))
+    }
+
+    #[test]
+    fn strings_in_tiles_are_one_letter_each() {
+        let mut expected = Vec::new();
+        append_zero_terminated_string(&mut expected, "aab");
+        append_zero_terminated_string(&mut expected, "aaa");
+
+        assert_eq!(tiles(r#"["aab", 0, "aaa", 0]"#).unwrap(), expected);
+    }
+
+    #[test]
+    fn empty_string_in_tiles_is_only_the_terminator() {
+        assert_eq!(tiles(r#"["", 0]"#).unwrap(), [Tile::num(0)]);
+    }
+
+    #[test]
+    fn register_holds_a_single_letter() {
+        let json = Json::from_str(r#"{"0": "a", "1": "ab"}"#).unwrap();
+        assert!(registers_from_json(&json).is_err());
+    }
+
+    #[test]
+    fn validate_fails_a_program_that_never_finishes() {
+        let program: Program = "a:\nJUMP a".parse().unwrap();
+        let result = level_1().validate(&program);
+
+        assert!(!result.passed);
+        assert_eq!(result.case, 0);
+        assert_eq!(result.error, Some(machine::Error::StepLimitExceeded));
+        assert_eq!(result.steps, STEP_LIMIT);
+    }
+
+    #[test]
+    fn expected_output_matches_every_case() {
+        for (number, level) in builtin_levels() {
+            for (input, output) in level.cases() {
+                assert_eq!(expected_output(number, &input, &level.registers), Some(output), "{}", level.name);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_mixed_escapes() {
+        assert_eq!(parse_mixed("#0").unwrap(), [Tile::num(0)]);
+        assert_eq!(parse_mixed("'1'").unwrap(), [Tile::Letter('1')]);
+        assert_eq!(parse_mixed("-1,ih,'2',#3").unwrap(),
+                   [Tile::num(-1), Tile::Letter('i'), Tile::Letter('h'), Tile::Letter('2'), Tile::num(3)]);
+    }
+
+    #[test]
+    fn parse_mixed_malformed_escapes() {
+        for s in &["#a", "#", "1,#", "'", "'ab"] {
+            match parse_mixed(s) {
+                Err(Error::InvalidTile) => {},
+                other => panic!("Expected an invalid tile for {:?}, got {:?}", s, other),
+            }
+        }
+    }
+
+    #[test]
+    fn expected_output_of_a_letter_where_a_number_belongs() {
+        let input = parse_mixed("3,a").unwrap();
+        assert_eq!(expected_output(14, &input, &BTreeMap::new()), None);
+        assert_eq!(expected_output(37, &input, &level_37().registers), None);
+        assert_eq!(expected_output(38, &input, &BTreeMap::new()), None);
+    }
+
+    #[test]
+    fn expected_output_of_an_unset_register() {
+        assert_eq!(expected_output(3, &Vec::new(), &BTreeMap::new()), None);
+        assert_eq!(expected_output(37, &from_numbers(&[5]), &level_37().registers), None);
+    }
+
+    #[test]
+    fn expected_output_of_a_cyclic_chain() {
+        let registers = registers_from(&[
+            (0, Tile::Letter('a')), (1, Tile::num(2)),
+            (2, Tile::Letter('b')), (3, Tile::num(0)),
+        ]);
+        assert_eq!(expected_output(37, &from_numbers(&[0]), &registers), None);
+    }
+}
@@ -0,0 +1,25 @@
+#[macro_use]
+extern crate peresil;
+extern crate rustc_serialize;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "proptest")]
+extern crate proptest;
+
+pub mod parser;
+pub mod compiler;
+pub mod machine;
+pub mod level;
+pub mod run;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Register {
+    Direct(u8),
+    Indirect(u8),
+}
@@ -1,12 +1,19 @@
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::error;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::rc::Rc;
 
 use super::Register;
 
 type AbsoluteIndex = usize;
 
 #[derive(Debug, Copy, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Instruction {
     Inbox,
+    InboxFrom(u8),
     Outbox,
     CopyFrom(Register),
     CopyTo(Register),
@@ -14,6 +21,14 @@ pub enum Instruction {
     BumpDown(Register),
     Add(Register),
     Sub(Register),
+    #[cfg(feature = "extended-ops")]
+    Zero(Register),
+    // Jumps to the instruction whose index is held in the register
+    #[cfg(feature = "extended-ops")]
+    JumpIndirect(Register),
+    // Fails unless the accumulator holds this number
+    #[cfg(feature = "assertions")]
+    AssertAccumulator(i16),
     Jump(AbsoluteIndex),
     JumpIfZero(AbsoluteIndex),
     JumpIfNegative(AbsoluteIndex),
@@ -21,70 +36,306 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    // Labels compile to `NoOp`, and like in the game they are free.
+    // Assertions aren't part of the game at all.
     pub fn counts_towards_stats(&self) -> bool {
-        if let Instruction::NoOp = *self { false }
-        else { true }
+        match *self {
+            Instruction::NoOp => false,
+            #[cfg(feature = "assertions")]
+            Instruction::AssertAccumulator(..) => false,
+            _ => true,
+        }
+    }
+
+    pub fn register(&self) -> Option<Register> {
+        use self::Instruction::*;
+
+        match *self {
+            CopyFrom(r) | CopyTo(r) | BumpUp(r) | BumpDown(r) | Add(r) | Sub(r) => Some(r),
+            #[cfg(feature = "extended-ops")]
+            Zero(r) | JumpIndirect(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    // The name the instruction is written with, ignoring its argument.
+    // Labels have no instruction of their own so get a name of their own.
+    pub fn mnemonic(&self) -> &'static str {
+        use self::Instruction::*;
+
+        match *self {
+            Inbox | InboxFrom(..) => "INBOX",
+            Outbox => "OUTBOX",
+            CopyFrom(..) => "COPYFROM",
+            CopyTo(..) => "COPYTO",
+            BumpUp(..) => "BUMPUP",
+            BumpDown(..) => "BUMPDN",
+            Add(..) => "ADD",
+            Sub(..) => "SUB",
+            #[cfg(feature = "extended-ops")]
+            Zero(..) => "ZERO",
+            #[cfg(feature = "extended-ops")]
+            JumpIndirect(..) => "JUMPI",
+            #[cfg(feature = "assertions")]
+            AssertAccumulator(..) => "ASSERTACC",
+            Jump(..) => "JUMP",
+            JumpIfZero(..) => "JUMPZ",
+            JumpIfNegative(..) => "JUMPN",
+            NoOp => "LABEL",
+        }
     }
 }
 
-// Clamped at [-999, 999]
+// Jump targets are shown as instruction indexes, as there are no
+// label names to use
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        let register = |r| match r {
+            Register::Direct(r) => r.to_string(),
+            Register::Indirect(r) => format!("[{}]", r),
+        };
+
+        let argument = match *self {
+            InboxFrom(n) => n.to_string(),
+            CopyFrom(r) | CopyTo(r) | BumpUp(r) | BumpDown(r) | Add(r) | Sub(r) => register(r),
+            #[cfg(feature = "extended-ops")]
+            Zero(r) | JumpIndirect(r) => register(r),
+            #[cfg(feature = "assertions")]
+            AssertAccumulator(n) => n.to_string(),
+            Jump(t) | JumpIfZero(t) | JumpIfNegative(t) => t.to_string(),
+            Inbox | Outbox | NoOp => return self.mnemonic().fmt(f),
+        };
+
+        write!(f, "{} {}", self.mnemonic(), argument)
+    }
+}
+
+// What happens when arithmetic leaves [-999, 999]. The game treats it
+// as an error, while `Saturate` holds the value at the limit it passed.
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    Error,
+    Saturate,
+}
+
+impl Default for ArithmeticMode {
+    fn default() -> ArithmeticMode { ArithmeticMode::Error }
+}
+
+// What `INBOX` does when its inbox has nothing left. The game halts,
+// which `run` and friends treat as the program finishing. With
+// `Sentinel` the tile is put in hand instead, as many times as `INBOX`
+// is reached, and with `Jump` the machine jumps to that instruction
+// leaving the hand alone. Either way `INBOX` never halts, so the
+// program has to finish by running off its end; one that always
+// returns to `INBOX` runs until a step limit or `run_detect_loop`
+// stops it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum EmptyInboxMode {
+    Halt,
+    Sentinel(Tile),
+    Jump(AbsoluteIndex),
+}
+
+impl Default for EmptyInboxMode {
+    fn default() -> EmptyInboxMode { EmptyInboxMode::Halt }
+}
+
+const ALPHABET: &'static str = "abcdefghijklmnopqrstuvwxyz";
+
+// Clamped at [-999, 999]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 struct NumberValue(i16);
 
 impl NumberValue {
+    // Letters are numbered by their position in `ALPHABET`, ignoring
+    // case: `a` and `A` are 1, `b` and `B` are 2, up to `z` and `Z` at
+    // 26. Subtracting one letter from another gives the distance
+    // between them, so `SUB` of `a` from `b` is 1 and of `c` from `a`
+    // is -2. `ADD` never looks at letters, as the game forbids it.
     fn from_char(c: char) -> Result<NumberValue, Error> {
-        NumberValue::clamp(c as i16)
+        let c = c.to_ascii_lowercase();
+        match ALPHABET.chars().position(|l| l == c) {
+            Some(position) => NumberValue::clamp(position as i32 + 1),
+            None => Err(Error::NonAlphabeticLetter),
+        }
     }
 
-    fn clamp(v: i16) -> Result<NumberValue, Error> {
+    // Arithmetic is done in `i32` so that it can't overflow before
+    // being brought back into range
+    fn clamp(v: i32) -> Result<NumberValue, Error> {
         if v > 999 {
             Err(Error::Overflow)
         } else if v < -999 {
             Err(Error::Underflow)
         } else {
-            Ok(NumberValue(v))
+            Ok(NumberValue(v as i16))
         }
     }
 
-    fn add(self, other: NumberValue) -> Result<NumberValue, Error> {
-        NumberValue::clamp(self.0 + other.0)
+    fn clamp_with(v: i32, mode: ArithmeticMode) -> Result<NumberValue, Error> {
+        match mode {
+            ArithmeticMode::Error => NumberValue::clamp(v),
+            ArithmeticMode::Saturate => Ok(NumberValue(v.max(-999).min(999) as i16)),
+        }
+    }
+
+    fn add(self, other: NumberValue, mode: ArithmeticMode) -> Result<NumberValue, Error> {
+        NumberValue::clamp_with(self.0 as i32 + other.0 as i32, mode)
     }
 
-    fn sub(self, other: NumberValue) -> Result<NumberValue, Error> {
-        NumberValue::clamp(self.0 - other.0)
+    fn sub(self, other: NumberValue, mode: ArithmeticMode) -> Result<NumberValue, Error> {
+        NumberValue::clamp_with(self.0 as i32 - other.0 as i32, mode)
     }
 
     fn is_zero(self) -> bool { self.0 == 0 }
     fn is_negative(self) -> bool { self.0 < 0 }
 
-    fn increment(self) -> Result<NumberValue, Error> {
-        NumberValue::clamp(self.0 + 1)
+    fn increment(self, mode: ArithmeticMode) -> Result<NumberValue, Error> {
+        NumberValue::clamp_with(self.0 as i32 + 1, mode)
     }
 
-    fn decrement(self) -> Result<NumberValue, Error> {
-        NumberValue::clamp(self.0 - 1)
+    fn decrement(self, mode: ArithmeticMode) -> Result<NumberValue, Error> {
+        NumberValue::clamp_with(self.0 as i32 - 1, mode)
     }
 
-    fn into_u8(self) -> u8 {
-        self.0 as u8 // Should have error of some kind?
+    fn into_u8(self) -> Result<u8, Error> {
+        if self.0 < 0 || self.0 > u8::MAX as i16 {
+            Err(Error::IndirectOutOfRange)
+        } else {
+            Ok(self.0 as u8)
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Tile {
     Number(NumberValue),
     Letter(char),
 }
 
 impl Tile {
+    // Only for numbers known to be in range
     pub fn num(i: i16) -> Tile {
-        Tile::Number(NumberValue::clamp(i).unwrap())
+        Tile::Number(NumberValue::clamp(i as i32).unwrap())
+    }
+
+    pub fn try_num(i: i16) -> Result<Tile, Error> {
+        NumberValue::clamp(i as i32).map(Tile::Number)
+    }
+
+    pub fn as_number(&self) -> Option<i16> {
+        match *self {
+            Tile::Number(v) => Some(v.0),
+            Tile::Letter(..) => None,
+        }
+    }
+
+    pub fn same_kind(&self, other: &Tile) -> bool {
+        self.kind() == other.kind()
+    }
+
+    pub fn kind(&self) -> TileKind {
+        match *self {
+            Tile::Number(..) => TileKind::Number,
+            Tile::Letter(..) => TileKind::Letter,
+        }
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TileKind {
+    Number,
+    Letter,
+}
+
+impl fmt::Display for Tile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Tile::Number(v) => v.0.fmt(f),
+            Tile::Letter(c) => c.fmt(f),
+        }
+    }
+}
+
+// Tiles are separated by spaces so that adjacent numbers stay distinct
+pub fn render_output(output: &Output) -> String {
+    let tiles: Vec<_> = output.iter().map(|t| t.to_string()).collect();
+    tiles.join(" ")
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputDifference {
+    pub position: usize,
+    // `None` when that output ended before `position`
+    pub expected: Option<Tile>,
+    pub actual: Option<Tile>,
+    // A few tiles either side of `position`
+    pub expected_context: Output,
+    pub actual_context: Output,
+}
+
+impl OutputDifference {
+    // A number where a letter was expected (or vice versa) is usually a
+    // mistake in the level rather than the program, so call it out
+    pub fn is_type_mismatch(&self) -> bool {
+        match (self.expected, self.actual) {
+            (Some(e), Some(a)) => !e.same_kind(&a),
+            _ => false,
+        }
+    }
+}
+
+const DIFFERENCE_CONTEXT: usize = 3;
+
+pub fn first_difference(expected: &Output, actual: &Output) -> Option<OutputDifference> {
+    let position = match expected.iter().zip(actual).position(|(e, a)| e != a) {
+        Some(position) => position,
+        None if expected.len() == actual.len() => return None,
+        None => expected.len().min(actual.len()),
+    };
+
+    let window = |output: &Output| {
+        let start = position.saturating_sub(DIFFERENCE_CONTEXT);
+        let end = (position + DIFFERENCE_CONTEXT + 1).min(output.len());
+        output.get(start..end).unwrap_or(&[]).to_vec()
+    };
+
+    Some(OutputDifference {
+        position: position,
+        expected: expected.get(position).cloned(),
+        actual: actual.get(position).cloned(),
+        expected_context: window(expected),
+        actual_context: window(actual),
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct GradeReport {
+    pub passed: bool,
+    pub expected_len: usize,
+    pub actual_len: usize,
+    pub difference: Option<OutputDifference>,
+}
+
+pub fn grade(expected: &Output, actual: &Output) -> GradeReport {
+    let difference = first_difference(expected, actual);
+
+    GradeReport {
+        passed: difference.is_none(),
+        expected_len: expected.len(),
+        actual_len: actual.len(),
+        difference: difference,
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Error {
     EndOfProgram,
+    InboxEmpty,
+    NoSuchInbox,
     IndirectThroughNil,
     IndirectThroughNegative,
     IndirectThroughLetter,
@@ -96,6 +347,7 @@ pub enum Error {
     AddWithNil,
     AddToNil,
     AddWithLetter,
+    AddCrossTypes,
     SubFromNil,
     SubWithNil,
     SubCrossTypes,
@@ -103,8 +355,69 @@ pub enum Error {
     JumpNegativeNil,
     Underflow,
     Overflow,
+    StepLimitExceeded,
+    InfiniteLoop,
+    OutputOverflow,
+    RegisterOutOfBounds,
+    TileKindMismatch,
+    IndirectOutOfRange,
+    NonAlphabeticLetter,
+    #[cfg(feature = "extended-ops")]
+    JumpIndirectNil,
+    #[cfg(feature = "extended-ops")]
+    InvalidJumpTarget,
+    #[cfg(feature = "assertions")]
+    AssertionFailed,
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        let msg = match *self {
+            EndOfProgram => "ran past the end of the program",
+            InboxEmpty => "there are no more tiles in the inbox",
+            NoSuchInbox => "tried to INBOX from an inbox that doesn't exist",
+            IndirectThroughNil => "tried to dereference a register that holds no tile",
+            IndirectThroughNegative => "tried to dereference a register that holds a negative number",
+            IndirectThroughLetter => "tried to dereference a register that holds a letter",
+            OutputNil => "tried to OUTBOX with nothing in hand",
+            CopyFromNil => "tried to COPYFROM a register that holds no tile",
+            CopyToNil => "tried to COPYTO with nothing in hand",
+            BumpNil => "tried to bump a register that holds no tile",
+            BumpLetter => "tried to bump a register that holds a letter",
+            AddWithNil => "tried to ADD a register that holds no tile",
+            AddToNil => "tried to ADD with nothing in hand",
+            AddWithLetter => "tried to ADD two letters",
+            AddCrossTypes => "tried to ADD a letter and a number",
+            SubFromNil => "tried to SUB with nothing in hand",
+            SubWithNil => "tried to SUB a register that holds no tile",
+            SubCrossTypes => "tried to SUB a letter and a number",
+            JumpZeroNil => "tried to JUMPZ with nothing in hand",
+            JumpNegativeNil => "tried to JUMPN with nothing in hand",
+            Underflow => "a number went below -999",
+            Overflow => "a number went above 999",
+            StepLimitExceeded => "the program ran for too many steps",
+            InfiniteLoop => "the program is stuck in a loop that will never finish",
+            OutputOverflow => "the program put too many tiles in the outbox",
+            RegisterOutOfBounds => "tried to use a register that is not on the floor",
+            TileKindMismatch => "tried to put the wrong kind of tile in a register",
+            IndirectOutOfRange => "tried to dereference a register that holds a number too large to be a register",
+            NonAlphabeticLetter => "tried to SUB a character that is not a letter",
+            #[cfg(feature = "extended-ops")]
+            JumpIndirectNil => "tried to JUMPI through a register that holds no tile",
+            #[cfg(feature = "extended-ops")]
+            InvalidJumpTarget => "tried to JUMPI to something that is not an instruction",
+            #[cfg(feature = "assertions")]
+            AssertionFailed => "ASSERTACC found a different value in hand",
+        };
+
+        msg.fmt(f)
+    }
+}
+
+impl error::Error for Error {}
+
 pub type Input = Vec<Tile>;
 pub type Output = Vec<Tile>;
 pub type Registers = BTreeMap<u8, Tile>;
@@ -115,44 +428,312 @@ pub struct Stats {
     pub memory_usage: usize,
 }
 
+#[derive(Debug, Clone)]
+pub struct CaseResult {
+    pub passed: bool,
+    pub output: Output,
+    pub error: Option<Error>,
+    pub stats: Stats,
+}
+
+// `CompletedNormally` means execution ran past the last instruction,
+// while `RanOutOfInput` means an `INBOX` found nothing left to take.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunOutcome {
+    CompletedNormally,
+    RanOutOfInput,
+}
+
+// The rules a level may place on a run. Each one that is `None`, as
+// they all are by default, adds no limit of its own. Breaking one stops
+// the run with `StepLimitExceeded`, `RegisterOutOfBounds` or
+// `OutputOverflow`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct RunConstraints {
+    pub max_steps: Option<usize>,
+    pub floor_size: Option<usize>,
+    pub max_output: Option<usize>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RunStop {
+    Halted,
+    Breakpoint(usize),
+    // A hook returned `ControlFlow::Break`
+    Aborted,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct TraceEntry {
+    pub pc: usize,
+    pub next_pc: usize,
+    pub instruction: Instruction,
+    pub accumulator_before: Option<Tile>,
+    pub accumulator_after: Option<Tile>,
+    pub register_written: Option<(u8, Tile)>,
+    pub output_written: Option<Tile>,
+}
+
+// What a step did that can be seen from outside the machine
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MachineEvent {
+    InboxRead(Tile),
+    OutboxWrite(Tile),
+    RegisterWrite(u8, Tile),
+    Jumped(usize),
+}
+
+// Tiles pulled from a stream are kept, as machines cloned from each
+// other share the stream but may be at different positions in it.
+struct TileStream {
+    tiles: Box<dyn Iterator<Item = Tile>>,
+    taken: Input,
+}
+
+impl fmt::Debug for TileStream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TileStream").field("taken", &self.taken).finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+enum InputSource {
+    Tiles(Input),
+    Stream(Rc<RefCell<TileStream>>),
+}
+
+impl InputSource {
+    fn get(&self, position: usize) -> Option<Tile> {
+        match *self {
+            InputSource::Tiles(ref tiles) => tiles.get(position).cloned(),
+            InputSource::Stream(ref stream) => {
+                let mut stream = stream.borrow_mut();
+                let stream = &mut *stream;
+                while stream.taken.len() <= position {
+                    match stream.tiles.next() {
+                        Some(t) => stream.taken.push(t),
+                        None => return None,
+                    }
+                }
+                Some(stream.taken[position])
+            },
+        }
+    }
+
+    // A stream only knows about the tiles already pulled from it
+    fn known_from(&self, position: usize) -> Input {
+        match *self {
+            InputSource::Tiles(ref tiles) => tiles.get(position..).unwrap_or(&[]).to_vec(),
+            InputSource::Stream(ref stream) => {
+                stream.borrow().taken.get(position..).unwrap_or(&[]).to_vec()
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MachineState {
+    input: InputSource,
+    input_position: usize,
+    other_inputs: Vec<(Input, usize)>,
+    output: Output,
+    pc: usize,
+    accumulator: Option<Tile>,
+    registers: Registers,
+    runtime: usize,
+    steps: usize,
+}
+
 #[derive(Debug, Clone)]
 pub struct Machine {
     program: Vec<Instruction>,
-    input: Input,
+    input: InputSource,
+    input_position: usize,
+    // Inboxes 1 and up, each with how many tiles have been taken
+    other_inputs: Vec<(Input, usize)>,
     output: Output,
     pc: usize,
     accumulator: Option<Tile>,
+    // What is in hand at the start, and again after a reset
+    initial_accumulator: Option<Tile>,
     registers: Registers,
     runtime: usize,
+    steps: usize,
+    floor_size: Option<usize>,
+    // Registers that may only ever hold one kind of tile
+    tile_kinds: BTreeMap<u8, TileKind>,
+    max_output: Option<usize>,
+    arithmetic_mode: ArithmeticMode,
+    empty_inbox_mode: EmptyInboxMode,
+    breakpoints: BTreeSet<usize>,
+    paused_at: Option<usize>,
+    // Only collected once asked for, so plain runs don't allocate
+    events: Option<Vec<MachineEvent>>,
 }
 
 impl Machine {
-    pub fn new<I>(program: I, mut input: Input, registers: Registers) -> Machine
+    pub fn new<I>(program: I, input: Input, registers: Registers) -> Machine
         where I: IntoIterator<Item = Instruction>
     {
-        // We want to pop off the front, so flip it around for efficiency.
-        input.reverse();
-
         Machine {
             program: program.into_iter().collect(),
-            input: input,
+            // Taking from the front is just moving past it, which
+            // keeps the remaining input in order.
+            input: InputSource::Tiles(input),
+            input_position: 0,
+            other_inputs: Vec::new(),
             output: Vec::new(),
             pc: 0,
             accumulator: None,
+            initial_accumulator: None,
             registers: registers,
             runtime: 0,
+            steps: 0,
+            floor_size: None,
+            tile_kinds: BTreeMap::new(),
+            max_output: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            empty_inbox_mode: EmptyInboxMode::default(),
+            breakpoints: BTreeSet::new(),
+            paused_at: None,
+            events: None,
+        }
+    }
+
+    // The first input is inbox 0, used by a plain `INBOX`, and the
+    // rest are taken from with `INBOX n`
+    pub fn new_with_inboxes<I>(program: I, mut inputs: Vec<Input>, registers: Registers) -> Machine
+        where I: IntoIterator<Item = Instruction>
+    {
+        let first = if inputs.is_empty() { Vec::new() } else { inputs.remove(0) };
+        let mut m = Machine::new(program, first, registers);
+        m.other_inputs = inputs.into_iter().map(|input| (input, 0)).collect();
+        m
+    }
+
+    // Tiles are only pulled from `input` when an `INBOX` needs one,
+    // so it may be endless
+    pub fn new_streaming<I, S>(program: I, input: S, registers: Registers) -> Machine
+        where I: IntoIterator<Item = Instruction>,
+              S: IntoIterator<Item = Tile>,
+              S::IntoIter: 'static,
+    {
+        let mut m = Machine::new(program, Vec::new(), registers);
+        m.input = InputSource::Stream(Rc::new(RefCell::new(TileStream {
+            tiles: Box::new(input.into_iter()),
+            taken: Vec::new(),
+        })));
+        m
+    }
+
+    // Starts the same program over, keeping the floor size, output
+    // limit, and breakpoints. Only inbox 0 is left.
+    pub fn reset(&mut self, input: Input, registers: Registers) {
+        self.input = InputSource::Tiles(input);
+        self.input_position = 0;
+        self.other_inputs.clear();
+        self.output.clear();
+        self.pc = 0;
+        self.accumulator = self.initial_accumulator;
+        self.registers = registers;
+        self.runtime = 0;
+        self.steps = 0;
+        self.paused_at = None;
+        if let Some(ref mut events) = self.events {
+            events.clear();
+        }
+    }
+
+    pub fn set_floor_size(&mut self, floor_size: usize) {
+        self.floor_size = Some(floor_size);
+    }
+
+    // Should be set before the first step, as it also replaces what
+    // is in hand right now
+    pub fn set_initial_accumulator(&mut self, tile: Option<Tile>) {
+        self.initial_accumulator = tile;
+        self.accumulator = tile;
+    }
+
+    pub fn set_tile_kinds(&mut self, tile_kinds: BTreeMap<u8, TileKind>) {
+        self.tile_kinds = tile_kinds;
+    }
+
+    // Starts collecting an event for everything each step does
+    pub fn record_events(&mut self) {
+        if self.events.is_none() {
+            self.events = Some(Vec::new());
+        }
+    }
+
+    // The events since the last call, leaving recording on
+    pub fn take_events(&mut self) -> Vec<MachineEvent> {
+        match self.events {
+            Some(ref mut events) => events.drain(..).collect(),
+            None => Vec::new(),
         }
     }
 
+    pub fn set_max_output(&mut self, max_output: usize) {
+        self.max_output = Some(max_output);
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) {
+        self.arithmetic_mode = mode;
+    }
+
+    pub fn set_empty_inbox_mode(&mut self, mode: EmptyInboxMode) {
+        self.empty_inbox_mode = mode;
+    }
+
+    // Gives the tile read from the inbox like a successful `INBOX`
+    // does, which is always `None` as a sentinel isn't from the inbox
+    fn inbox_empty(&mut self) -> Result<Option<Tile>, Error> {
+        match self.empty_inbox_mode {
+            EmptyInboxMode::Halt => return Err(Error::InboxEmpty),
+            EmptyInboxMode::Sentinel(t) => self.accumulator = Some(t),
+            EmptyInboxMode::Jump(i) => self.pc = i,
+        }
+        Ok(None)
+    }
+
+    // Runs a copy of this machine once per case, replacing the input
+    // each time. Everything else, including registers, starts as it
+    // is now.
+    pub fn run_all_cases(&self, cases: &[(Input, Output)]) -> Vec<CaseResult> {
+        cases.iter().map(|&(ref input, ref expected)| {
+            let mut m = self.clone();
+            m.input = InputSource::Tiles(input.clone());
+            m.input_position = 0;
+
+            let error = m.run().err();
+            let passed = error.is_none() && &m.output == expected;
+
+            CaseResult {
+                passed: passed,
+                stats: m.stats(),
+                output: m.output,
+                error: error,
+            }
+        }).collect()
+    }
+
     fn deref_target(&self, r: Register) -> Result<u8, Error> {
-        match r {
-            Register::Direct(r) => Ok(r),
+        let r = match r {
+            Register::Direct(r) => r,
+            // Only an empty register is nil; one holding 0 points at
+            // register 0 like any other number would
             Register::Indirect(r) => match self.registers.get(&r) {
-                None => Err(Error::IndirectThroughNil),
-                Some(&Tile::Number(v)) if v.is_negative() => Err(Error::IndirectThroughNegative),
-                Some(&Tile::Number(v)) => Ok(v.into_u8()), // Should have max # registers?
-                Some(&Tile::Letter(..)) => Err(Error::IndirectThroughLetter),
+                None => return Err(Error::IndirectThroughNil),
+                Some(&Tile::Number(v)) if v.is_negative() => return Err(Error::IndirectThroughNegative),
+                Some(&Tile::Number(v)) => try!(v.into_u8()),
+                Some(&Tile::Letter(..)) => return Err(Error::IndirectThroughLetter),
             },
+        };
+
+        match self.floor_size {
+            Some(size) if r as usize >= size => Err(Error::RegisterOutOfBounds),
+            _ => Ok(r),
         }
     }
 
@@ -163,33 +744,252 @@ impl Machine {
         }
     }
 
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    // The instruction that the next `step` will execute
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        self.program.get(self.pc).cloned()
+    }
+
+    pub fn accumulator(&self) -> Option<Tile> {
+        self.accumulator
+    }
+
     pub fn output(&self) -> &Output {
         &self.output
     }
 
-    pub fn step(&mut self) -> Result<(), Error> {
-        use self::Instruction::*;
+    pub fn registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    // In the order the program will take them. Streamed input only
+    // includes the tiles already pulled from the stream.
+    pub fn remaining_input(&self) -> Input {
+        self.input.known_from(self.input_position)
+    }
 
-        if self.pc >= self.program.len() {
-            return Err(Error::EndOfProgram);
+    // Borrows the remaining tiles, in the same order. Streamed input has
+    // no tiles to borrow, so it gives `None`.
+    pub fn remaining_tiles(&self) -> Option<&[Tile]> {
+        match self.input {
+            InputSource::Tiles(ref tiles) => Some(tiles.get(self.input_position..).unwrap_or(&[])),
+            InputSource::Stream(..) => None,
         }
+    }
 
-        // println!("PC: {}", self.pc);
-        // println!("Instr: {:?}", self.program[self.pc]);
-        // println!("Acc: {:?}", self.accumulator);
+    pub fn set_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.insert(pc);
+    }
 
-        let instruction = self.program[self.pc];
+    pub fn clear_breakpoint(&mut self, pc: usize) {
+        self.breakpoints.remove(&pc);
+    }
+
+    // Stops before executing an instruction with a breakpoint. Calling
+    // this again resumes from that instruction.
+    pub fn run_until_breakpoint(&mut self) -> Result<RunStop, Error> {
+        // Don't immediately stop at the breakpoint we paused at
+        let mut resuming = self.paused_at.take() == Some(self.pc);
+
+        loop {
+            if !resuming && self.breakpoints.contains(&self.pc) {
+                self.paused_at = Some(self.pc);
+                return Ok(RunStop::Breakpoint(self.pc));
+            }
+            resuming = false;
+
+            match self.step() {
+                Ok(..) => continue,
+                Err(Error::EndOfProgram) |
+                Err(Error::InboxEmpty) => return Ok(RunStop::Halted),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Calls `hook` before each step, stopping if it breaks. Once the
+    // program has finished the hook is not called again.
+    pub fn run_with_hook<F>(&mut self, mut hook: F) -> Result<RunStop, Error>
+        where F: FnMut(&Machine) -> ControlFlow<()>
+    {
+        loop {
+            if self.current_instruction().is_none() {
+                return Ok(RunStop::Halted);
+            }
+            if let ControlFlow::Break(()) = hook(self) {
+                return Ok(RunStop::Aborted);
+            }
+
+            match self.step() {
+                Ok(..) => {},
+                Err(Error::EndOfProgram) |
+                Err(Error::InboxEmpty) => return Ok(RunStop::Halted),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn run_traced(&mut self) -> (Result<(), Error>, Vec<TraceEntry>) {
+        self.run_traced_with_limit(usize::MAX)
+    }
+
+    pub fn run_traced_with_limit(&mut self, max_steps: usize) -> (Result<(), Error>, Vec<TraceEntry>) {
+        let mut trace = Vec::new();
+
+        loop {
+            if trace.len() >= max_steps {
+                return (Err(Error::StepLimitExceeded), trace);
+            }
+
+            match self.step_traced() {
+                Ok(entry) => trace.push(entry),
+                Err(Error::EndOfProgram) |
+                Err(Error::InboxEmpty) => return (Ok(()), trace),
+                Err(e) => return (Err(e), trace),
+            }
+        }
+    }
+
+    // Like `step`, but also reports what the instruction changed
+    pub fn step_traced(&mut self) -> Result<TraceEntry, Error> {
+        let pc = self.pc;
+        let instruction = match self.current_instruction() {
+            Some(i) => i,
+            None => return Err(Error::EndOfProgram),
+        };
+        let accumulator = self.accumulator;
+        let output_len = self.output.len();
+        let written = self.register_written_by(instruction);
+
+        try!(self.step());
+
+        Ok(TraceEntry {
+            pc: pc,
+            next_pc: self.pc,
+            instruction: instruction,
+            accumulator_before: accumulator,
+            accumulator_after: self.accumulator,
+            register_written: written.and_then(|r| self.registers.get(&r).map(|&t| (r, t))),
+            output_written: self.output.get(output_len).cloned(),
+        })
+    }
+
+    // Found before the instruction runs, as it may change the register
+    // that an indirect reference goes through
+    fn register_written_by(&self, instruction: Instruction) -> Option<u8> {
+        match instruction {
+            Instruction::CopyTo(r) |
+            Instruction::BumpUp(r) |
+            Instruction::BumpDown(r) => self.deref_target(r).ok(),
+            #[cfg(feature = "extended-ops")]
+            Instruction::Zero(r) => self.deref_target(r).ok(),
+            _ => None,
+        }
+    }
+
+    fn push_events(&mut self, pc: usize, instruction: Instruction, read: Option<Tile>, written: Option<u8>) {
+        let events = match self.events {
+            Some(ref mut events) => events,
+            None => return,
+        };
+
+        events.extend(read.map(MachineEvent::InboxRead));
+        if let Instruction::Outbox = instruction {
+            events.extend(self.output.last().cloned().map(MachineEvent::OutboxWrite));
+        }
+
+        if let Some(r) = written {
+            events.extend(self.registers.get(&r).map(|&t| MachineEvent::RegisterWrite(r, t)));
+        }
+
+        if self.pc != pc + 1 {
+            events.push(MachineEvent::Jumped(self.pc));
+        }
+    }
+
+    pub fn snapshot(&self) -> MachineState {
+        MachineState {
+            input: self.input.clone(),
+            input_position: self.input_position,
+            other_inputs: self.other_inputs.clone(),
+            output: self.output.clone(),
+            pc: self.pc,
+            accumulator: self.accumulator,
+            registers: self.registers.clone(),
+            runtime: self.runtime,
+            steps: self.steps,
+        }
+    }
+
+    pub fn restore(&mut self, state: MachineState) {
+        self.input = state.input;
+        self.input_position = state.input_position;
+        self.other_inputs = state.other_inputs;
+        self.output = state.output;
+        self.pc = state.pc;
+        self.accumulator = state.accumulator;
+        self.registers = state.registers;
+        self.runtime = state.runtime;
+        self.steps = state.steps;
+    }
+
+    fn check_tile_kind(&self, register: u8, tile: Tile) -> Result<(), Error> {
+        match self.tile_kinds.get(&register) {
+            Some(&kind) if kind != tile.kind() => Err(Error::TileKindMismatch),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn step(&mut self) -> Result<(), Error> {
+        use self::Instruction::*;
+
+        let instruction = match self.program.get(self.pc) {
+            Some(&i) => i,
+            None => return Err(Error::EndOfProgram),
+        };
+        let pc = self.pc;
+        let written = if self.events.is_some() { self.register_written_by(instruction) } else { None };
         self.pc += 1;
+        // The tile an `INBOX` took, if there was one to take
+        let mut read = None;
 
         match instruction {
-            Inbox => {
-                match self.input.pop() {
-                    Some(v) => self.accumulator = Some(v),
-                    None => return Err(Error::EndOfProgram),
-                }
+            Inbox | InboxFrom(0) => {
+                read = match self.input.get(self.input_position) {
+                    Some(v) => {
+                        self.input_position += 1;
+                        self.accumulator = Some(v);
+                        Some(v)
+                    },
+                    None => try!(self.inbox_empty()),
+                };
+            },
+            InboxFrom(n) => {
+                let &mut (ref input, ref mut position) = match self.other_inputs.get_mut(n as usize - 1) {
+                    Some(inbox) => inbox,
+                    None => return Err(Error::NoSuchInbox),
+                };
+                read = match input.get(*position) {
+                    Some(&v) => {
+                        *position += 1;
+                        self.accumulator = Some(v);
+                        Some(v)
+                    },
+                    None => try!(self.inbox_empty()),
+                };
             },
             Outbox => {
                 match self.accumulator {
+                    Some(_) if self.max_output.map_or(false, |max| self.output.len() >= max) => {
+                        return Err(Error::OutputOverflow)
+                    },
                     Some(v) => self.output.push(v),
                     None => return Err(Error::OutputNil),
                 }
@@ -203,6 +1003,7 @@ impl Machine {
                 match self.accumulator {
                     Some(v) => {
                         let r = try!(self.deref_target(r));
+                        try!(self.check_tile_kind(r, v));
                         self.registers.insert(r, v);
                     },
                     None => return Err(Error::CopyToNil),
@@ -213,7 +1014,7 @@ impl Machine {
                 let v = match self.registers.get_mut(&r) {
                     None => return Err(Error::BumpNil),
                     Some(&mut Tile::Number(ref mut v)) => {
-                        *v = try!(v.increment());
+                        *v = try!(v.increment(self.arithmetic_mode));
                         *v
                     },
                     Some(&mut Tile::Letter(..)) => return Err(Error::BumpLetter)
@@ -225,20 +1026,28 @@ impl Machine {
                 let v = match self.registers.get_mut(&r) {
                     None => return Err(Error::BumpNil),
                     Some(&mut Tile::Number(ref mut v)) => {
-                        *v = try!(v.decrement());
+                        *v = try!(v.decrement(self.arithmetic_mode));
                         *v
                     },
                     Some(&mut Tile::Letter(..)) => return Err(Error::BumpLetter)
                 };
                 self.accumulator = Some(Tile::Number(v))
             },
+            // | hand   | register | ADD           | SUB           |
+            // |--------|----------|---------------|---------------|
+            // | number | number   | sum           | difference    |
+            // | letter | letter   | AddWithLetter | distance      |
+            // | number | letter   | AddCrossTypes | SubCrossTypes |
+            // | letter | number   | AddCrossTypes | SubCrossTypes |
             Add(r) => {
                 let r = try!(self.deref_target(r));
                 let v = match (self.accumulator, self.registers.get(&r)) {
                     (None, _) => return Err(Error::AddToNil),
                     (_, None) => return Err(Error::AddWithNil),
-                    (Some(Tile::Number(a)), Some(&Tile::Number(v))) => try!(a.add(v)),
-                    _ => return Err(Error::AddWithLetter),
+                    (Some(Tile::Number(a)), Some(&Tile::Number(v))) => try!(a.add(v, self.arithmetic_mode)),
+                    (Some(Tile::Letter(..)), Some(&Tile::Letter(..))) => return Err(Error::AddWithLetter),
+                    (Some(Tile::Number(..)), Some(&Tile::Letter(..))) |
+                    (Some(Tile::Letter(..)), Some(&Tile::Number(..))) => return Err(Error::AddCrossTypes),
                 };
                 self.accumulator = Some(Tile::Number(v));
             },
@@ -247,16 +1056,43 @@ impl Machine {
                 let v = match (self.accumulator, self.registers.get(&r)) {
                     (None, _) => return Err(Error::SubFromNil),
                     (_, None) => return Err(Error::SubWithNil),
-                    (Some(Tile::Number(a)), Some(&Tile::Number(v))) => try!(a.sub(v)),
+                    (Some(Tile::Number(a)), Some(&Tile::Number(v))) => try!(a.sub(v, self.arithmetic_mode)),
                     (Some(Tile::Letter(a)), Some(&Tile::Letter(v))) => {
                         let a = try!(NumberValue::from_char(a));
                         let v = try!(NumberValue::from_char(v));
-                        try!(a.sub(v))
+                        try!(a.sub(v, self.arithmetic_mode))
                     },
-                    _ => return Err(Error::SubCrossTypes),
+                    (Some(Tile::Number(..)), Some(&Tile::Letter(..))) |
+                    (Some(Tile::Letter(..)), Some(&Tile::Number(..))) => return Err(Error::SubCrossTypes),
                 };
                 self.accumulator = Some(Tile::Number(v))
             },
+            #[cfg(feature = "extended-ops")]
+            Zero(r) => {
+                let r = try!(self.deref_target(r));
+                try!(self.check_tile_kind(r, Tile::num(0)));
+                self.registers.insert(r, Tile::num(0));
+            },
+            // Targets count every compiled instruction, labels included
+            #[cfg(feature = "extended-ops")]
+            JumpIndirect(r) => {
+                let r = try!(self.deref_target(r));
+                let target = match self.registers.get(&r) {
+                    None => return Err(Error::JumpIndirectNil),
+                    Some(&Tile::Number(v)) if !v.is_negative() => v.0 as usize,
+                    Some(..) => return Err(Error::InvalidJumpTarget),
+                };
+                if target >= self.program.len() {
+                    return Err(Error::InvalidJumpTarget);
+                }
+                self.pc = target;
+            },
+            #[cfg(feature = "assertions")]
+            AssertAccumulator(n) => {
+                if self.accumulator.and_then(|t| t.as_number()) != Some(n) {
+                    return Err(Error::AssertionFailed);
+                }
+            },
             Jump(i) => self.pc = i,
             JumpIfZero(i) => {
                 match self.accumulator {
@@ -281,20 +1117,461 @@ impl Machine {
             NoOp => {},
         }
 
+        self.push_events(pc, instruction, read, written);
+
         if instruction.counts_towards_stats() {
             self.runtime += 1;
         }
+        self.steps += 1;
 
         Ok(())
     }
 
     pub fn run(&mut self) -> Result<(), Error> {
+        self.run_with_limit(usize::MAX)
+    }
+
+    pub fn run_with_limit(&mut self, max_steps: usize) -> Result<(), Error> {
+        self.run_outcome_with_limit(max_steps).map(|_| ())
+    }
+
+    pub fn run_outcome(&mut self) -> Result<RunOutcome, Error> {
+        self.run_outcome_with_limit(usize::MAX)
+    }
+
+    // Counts how many times the instruction at each pc was executed
+    pub fn run_profiled(&mut self) -> (Result<(), Error>, BTreeMap<usize, usize>) {
+        self.run_profiled_with_limit(usize::MAX)
+    }
+
+    pub fn run_profiled_with_limit(&mut self, max_steps: usize) -> (Result<(), Error>, BTreeMap<usize, usize>) {
+        let mut counts = BTreeMap::new();
+
+        for _ in 0..max_steps {
+            let pc = self.pc;
+
+            match self.step() {
+                Ok(..) => *counts.entry(pc).or_insert(0) += 1,
+                Err(Error::EndOfProgram) |
+                Err(Error::InboxEmpty) => return (Ok(()), counts),
+                Err(e) => return (Err(e), counts),
+            }
+        }
+
+        (Err(Error::StepLimitExceeded), counts)
+    }
+
+    // Steps until the next tile is put in the outbox, giving `None`
+    // once the program has finished. Calling this again carries on from
+    // where the last call stopped, and keeps giving `None` once done.
+    pub fn run_to_next_output(&mut self) -> Result<Option<Tile>, Error> {
+        let output_len = self.output.len();
+
+        while self.output.len() == output_len {
+            let pc = self.pc;
+
+            match self.step() {
+                Ok(..) => {},
+                Err(Error::EndOfProgram) => return Ok(None),
+                // The pc has already moved past the `INBOX`, which would
+                // let the next call carry on with the old tile in hand
+                Err(Error::InboxEmpty) => {
+                    self.pc = pc;
+                    return Ok(None);
+                },
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(self.output.last().cloned())
+    }
+
+    // Without touching the inbox or outbox, each step depends only on
+    // the pc, accumulator, and registers. Seeing the same combination
+    // twice means the program will repeat itself forever. Any I/O
+    // makes the earlier states unreachable, so they are forgotten.
+    pub fn run_detect_loop(&mut self) -> Result<(), Error> {
+        let mut seen = HashSet::new();
+
         loop {
+            let input_taken = self.input_taken();
+            let output_len = self.output.len();
+
+            match self.step() {
+                Ok(..) => {},
+                Err(Error::EndOfProgram) |
+                Err(Error::InboxEmpty) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+
+            if self.input_taken() != input_taken || self.output.len() != output_len {
+                seen.clear();
+            }
+
+            if !seen.insert((self.pc, self.accumulator, self.registers.clone())) {
+                return Err(Error::InfiniteLoop);
+            }
+        }
+    }
+
+    // Across every inbox
+    fn input_taken(&self) -> usize {
+        self.input_position + self.other_inputs.iter().map(|&(_, p)| p).sum::<usize>()
+    }
+
+    // Where a constraint is `None` the machine's own floor size and
+    // output limit still apply, and they are put back after the run
+    pub fn run_constrained(&mut self, constraints: RunConstraints) -> Result<RunOutcome, Error> {
+        let (floor_size, max_output) = (self.floor_size, self.max_output);
+        self.floor_size = constraints.floor_size.or(floor_size);
+        self.max_output = constraints.max_output.or(max_output);
+
+        let result = self.run_outcome_with_limit(constraints.max_steps.unwrap_or(usize::MAX));
+
+        self.floor_size = floor_size;
+        self.max_output = max_output;
+        result
+    }
+
+    fn run_outcome_with_limit(&mut self, max_steps: usize) -> Result<RunOutcome, Error> {
+        let mut steps = 0;
+
+        loop {
+            if steps >= max_steps {
+                return Err(Error::StepLimitExceeded);
+            }
+            steps += 1;
+
             match self.step() {
                 Ok(..) => continue,
-                Err(Error::EndOfProgram) => return Ok(()),
+                Err(Error::EndOfProgram) => return Ok(RunOutcome::CompletedNormally),
+                Err(Error::InboxEmpty) => return Ok(RunOutcome::RanOutOfInput),
                 Err(e) => return Err(e),
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::Instruction::*;
+    use super::super::Register::*;
+
+    // Runs one instruction on register 0, giving what ends up in hand
+    fn hand_after(instruction: Instruction, hand: Option<Tile>, register: Option<Tile>) -> Result<Option<Tile>, Error> {
+        hand_after_with_mode(instruction, hand, register, ArithmeticMode::default())
+    }
+
+    fn hand_after_with_mode(instruction: Instruction, hand: Option<Tile>, register: Option<Tile>, mode: ArithmeticMode)
+                            -> Result<Option<Tile>, Error>
+    {
+        let mut m = Machine::new(vec![instruction], vec![], register.into_iter().map(|t| (0, t)).collect());
+        m.set_initial_accumulator(hand);
+        m.set_arithmetic_mode(mode);
+        m.step().map(|_| m.accumulator())
+    }
+
+    #[test]
+    fn add_combinations() {
+        let add = |hand, register| hand_after(Add(Direct(0)), hand, register);
+        let (n, l) = (Some(Tile::num(3)), Some(Tile::Letter('b')));
+
+        assert_eq!(add(n, Some(Tile::num(4))), Ok(Some(Tile::num(7))));
+        assert_eq!(add(l, l), Err(Error::AddWithLetter));
+        assert_eq!(add(n, l), Err(Error::AddCrossTypes));
+        assert_eq!(add(l, n), Err(Error::AddCrossTypes));
+        assert_eq!(add(None, n), Err(Error::AddToNil));
+        assert_eq!(add(n, None), Err(Error::AddWithNil));
+    }
+
+    #[test]
+    fn sub_combinations() {
+        let sub = |hand, register| hand_after(Sub(Direct(0)), hand, register);
+        let (n, l) = (Some(Tile::num(3)), Some(Tile::Letter('b')));
+
+        assert_eq!(sub(n, Some(Tile::num(4))), Ok(Some(Tile::num(-1))));
+        assert_eq!(sub(l, Some(Tile::Letter('e'))), Ok(Some(Tile::num(-3))));
+        assert_eq!(sub(n, l), Err(Error::SubCrossTypes));
+        assert_eq!(sub(l, n), Err(Error::SubCrossTypes));
+        assert_eq!(sub(None, n), Err(Error::SubFromNil));
+        assert_eq!(sub(n, None), Err(Error::SubWithNil));
+    }
+
+    fn arithmetic(mode: ArithmeticMode) -> Vec<Result<Option<Tile>, Error>> {
+        let run = |instruction, hand: Option<i16>, register: i16| {
+            hand_after_with_mode(instruction, hand.map(Tile::num), Some(Tile::num(register)), mode)
+        };
+
+        vec![
+            run(BumpUp(Direct(0)), None, 998),
+            run(BumpUp(Direct(0)), None, 999),
+            run(BumpDown(Direct(0)), None, -998),
+            run(BumpDown(Direct(0)), None, -999),
+            run(Add(Direct(0)), Some(500), 499),
+            run(Add(Direct(0)), Some(999), 999),
+            run(Sub(Direct(0)), Some(-500), 499),
+            run(Sub(Direct(0)), Some(-999), 999),
+        ]
+    }
+
+    #[test]
+    fn arithmetic_errors_past_999() {
+        let (max, min) = (Ok(Some(Tile::num(999))), Ok(Some(Tile::num(-999))));
+        assert_eq!(arithmetic(ArithmeticMode::Error), [
+            max, Err(Error::Overflow),
+            min, Err(Error::Underflow),
+            max, Err(Error::Overflow),
+            min, Err(Error::Underflow),
+        ]);
+    }
+
+    #[test]
+    fn arithmetic_saturates_at_999() {
+        let (max, min) = (Ok(Some(Tile::num(999))), Ok(Some(Tile::num(-999))));
+        assert_eq!(arithmetic(ArithmeticMode::Saturate), [max, max, min, min, max, max, min, min]);
+    }
+
+    #[test]
+    fn arithmetic_on_extreme_values() {
+        let (max, min) = (NumberValue(999), NumberValue(-999));
+
+        for &mode in &[ArithmeticMode::Error, ArithmeticMode::Saturate] {
+            let expected = |v: i16, e: Error| match mode {
+                ArithmeticMode::Error => Err(e),
+                ArithmeticMode::Saturate => Ok(NumberValue(v)),
+            };
+
+            assert_eq!(max.add(max, mode), expected(999, Error::Overflow));
+            assert_eq!(min.add(min, mode), expected(-999, Error::Underflow));
+            assert_eq!(max.sub(min, mode), expected(999, Error::Overflow));
+            assert_eq!(min.sub(max, mode), expected(-999, Error::Underflow));
+            assert_eq!(max.add(min, mode), Ok(NumberValue(0)));
+        }
+
+        assert_eq!(NumberValue::clamp(i16::MAX as i32 + 1), Err(Error::Overflow));
+        assert_eq!(NumberValue::clamp(i16::MIN as i32 - 1), Err(Error::Underflow));
+    }
+
+    #[test]
+    fn letters_are_numbered_from_a_to_z() {
+        assert_eq!(NumberValue::from_char('a'), Ok(NumberValue(1)));
+        assert_eq!(NumberValue::from_char('A'), Ok(NumberValue(1)));
+        assert_eq!(NumberValue::from_char('z'), Ok(NumberValue(26)));
+        assert_eq!(NumberValue::from_char('Z'), Ok(NumberValue(26)));
+        assert_eq!(NumberValue::from_char('`'), Err(Error::NonAlphabeticLetter));
+        assert_eq!(NumberValue::from_char('{'), Err(Error::NonAlphabeticLetter));
+    }
+
+    #[test]
+    fn sub_letters_at_the_ends_of_the_alphabet() {
+        let sub = |hand, register| hand_after(Sub(Direct(0)), Some(Tile::Letter(hand)), Some(Tile::Letter(register)));
+
+        assert_eq!(sub('b', 'a'), Ok(Some(Tile::num(1))));
+        assert_eq!(sub('z', 'a'), Ok(Some(Tile::num(25))));
+        assert_eq!(sub('a', 'z'), Ok(Some(Tile::num(-25))));
+        assert_eq!(sub('z', 'z'), Ok(Some(Tile::num(0))));
+        assert_eq!(sub('a', 'A'), Ok(Some(Tile::num(0))));
+    }
+
+    fn copy_from_through(pointer: Option<Tile>) -> Result<Option<Tile>, Error> {
+        let mut registers: Registers = vec![(0, Tile::Letter('z')), (3, Tile::Letter('c'))].into_iter().collect();
+        registers.extend(pointer.map(|t| (1, t)));
+
+        let mut m = Machine::new(vec![CopyFrom(Indirect(1))], vec![], registers);
+        m.step().map(|_| m.accumulator())
+    }
+
+    #[test]
+    fn indirect_through_zero_reads_register_zero() {
+        assert_eq!(copy_from_through(Some(Tile::num(0))), Ok(Some(Tile::Letter('z'))));
+    }
+
+    #[test]
+    fn indirect_through_a_positive_number() {
+        assert_eq!(copy_from_through(Some(Tile::num(3))), Ok(Some(Tile::Letter('c'))));
+    }
+
+    #[test]
+    fn indirect_through_nil() {
+        assert_eq!(copy_from_through(None), Err(Error::IndirectThroughNil));
+    }
+
+    #[test]
+    fn indirect_through_a_negative_number() {
+        assert_eq!(copy_from_through(Some(Tile::num(-1))), Err(Error::IndirectThroughNegative));
+    }
+
+    #[test]
+    fn indirect_through_a_letter() {
+        assert_eq!(copy_from_through(Some(Tile::Letter('a'))), Err(Error::IndirectThroughLetter));
+    }
+
+    #[test]
+    fn run_constrained_keeps_the_machine_limits() {
+        let mut m = Machine::new(vec![Inbox, CopyTo(Direct(3)), Outbox], vec![Tile::num(1)], Registers::new());
+        m.set_floor_size(3);
+        let fresh = m.clone();
+        assert_eq!(m.run_constrained(RunConstraints::default()), Err(Error::RegisterOutOfBounds));
+
+        let mut m = fresh.clone();
+        let constraints = RunConstraints { floor_size: Some(4), ..RunConstraints::default() };
+        assert_eq!(m.run_constrained(constraints), Ok(RunOutcome::CompletedNormally));
+
+        // The larger floor was only for that run
+        m.reset(vec![Tile::num(1)], Registers::new());
+        assert_eq!(m.run_constrained(RunConstraints::default()), Err(Error::RegisterOutOfBounds));
+    }
+
+    #[test]
+    fn run_constrained_keeps_the_output_limit() {
+        let mut m = Machine::new(vec![Inbox, Outbox, Outbox], vec![Tile::num(1)], Registers::new());
+        m.set_max_output(1);
+        assert_eq!(m.run_constrained(RunConstraints::default()), Err(Error::OutputOverflow));
+    }
+
+    #[test]
+    fn inbox_on_an_empty_inbox() {
+        let mut m = Machine::new(vec![Inbox], vec![], Registers::new());
+        assert_eq!(m.step(), Err(Error::InboxEmpty));
+
+        let mut m = Machine::new(vec![Inbox], vec![], Registers::new());
+        assert_eq!(m.run(), Ok(()));
+    }
+
+    #[test]
+    fn remaining_input_in_the_order_it_is_taken() {
+        let mut m = Machine::new(vec![Inbox], vec![Tile::num(1), Tile::num(2), Tile::num(3)], Registers::new());
+        assert_eq!(m.step(), Ok(()));
+        assert_eq!(m.remaining_input(), [Tile::num(2), Tile::num(3)]);
+        assert_eq!(m.remaining_tiles(), Some(&[Tile::num(2), Tile::num(3)][..]));
+
+        let mut m = Machine::new_streaming(vec![Inbox], vec![Tile::num(1), Tile::num(2)], Registers::new());
+        assert_eq!(m.step(), Ok(()));
+        assert_eq!(m.remaining_tiles(), None);
+    }
+
+    #[test]
+    fn running_off_the_end_of_the_program() {
+        let mut m = Machine::new(vec![NoOp], vec![], Registers::new());
+        assert_eq!(m.step(), Ok(()));
+        assert_eq!(m.step(), Err(Error::EndOfProgram));
+    }
+
+    fn events_with_empty_inbox(program: Vec<Instruction>, mode: EmptyInboxMode) -> Vec<MachineEvent> {
+        let mut m = Machine::new(program, vec![Tile::num(5)], Registers::new());
+        m.set_empty_inbox_mode(mode);
+        m.record_events();
+        assert_eq!(m.run(), Ok(()));
+        m.take_events()
+    }
+
+    #[test]
+    fn sentinel_is_not_read_from_the_inbox() {
+        let events = events_with_empty_inbox(vec![Inbox, Inbox, Outbox], EmptyInboxMode::Sentinel(Tile::num(0)));
+        assert_eq!(events, [MachineEvent::InboxRead(Tile::num(5)), MachineEvent::OutboxWrite(Tile::num(0))]);
+    }
+
+    #[test]
+    fn jump_on_empty_inbox_reads_nothing() {
+        let events = events_with_empty_inbox(vec![Inbox, Inbox, Outbox, NoOp], EmptyInboxMode::Jump(3));
+        assert_eq!(events, [MachineEvent::InboxRead(Tile::num(5)), MachineEvent::Jumped(3)]);
+    }
+
+    #[test]
+    fn inbox_reads_the_numbered_stream() {
+        let program = vec![InboxFrom(1), Outbox, Inbox, Outbox, InboxFrom(0), Outbox, InboxFrom(1)];
+        let inputs = vec![vec![Tile::num(1), Tile::num(2)], vec![Tile::Letter('a')]];
+
+        let mut m = Machine::new_with_inboxes(program, inputs, Registers::new());
+        assert_eq!(m.run_outcome(), Ok(RunOutcome::RanOutOfInput));
+        assert_eq!(m.output(), &[Tile::Letter('a'), Tile::num(1), Tile::num(2)]);
+    }
+
+    #[test]
+    fn inbox_that_does_not_exist() {
+        let inputs = vec![vec![Tile::num(1)], vec![Tile::num(2)]];
+
+        let mut m = Machine::new_with_inboxes(vec![InboxFrom(2)], inputs, Registers::new());
+        assert_eq!(m.run(), Err(Error::NoSuchInbox));
+
+        let mut m = Machine::new(vec![InboxFrom(1)], vec![Tile::num(1)], Registers::new());
+        assert_eq!(m.run(), Err(Error::NoSuchInbox));
+    }
+
+    #[test]
+    fn run_to_next_output_after_the_end() {
+        let program = vec![NoOp, Inbox, Outbox, Jump(0)];
+        let mut m = Machine::new(program, vec![Tile::num(1), Tile::num(2)], Registers::new());
+
+        assert_eq!(m.run_to_next_output(), Ok(Some(Tile::num(1))));
+        assert_eq!(m.run_to_next_output(), Ok(Some(Tile::num(2))));
+        assert_eq!(m.run_to_next_output(), Ok(None));
+        assert_eq!(m.run_to_next_output(), Ok(None));
+        assert_eq!(m.output(), &[Tile::num(1), Tile::num(2)]);
+
+        let mut m = Machine::new(vec![Inbox, Outbox], vec![Tile::num(1)], Registers::new());
+        assert_eq!(m.run_to_next_output(), Ok(Some(Tile::num(1))));
+        assert_eq!(m.run_to_next_output(), Ok(None));
+        assert_eq!(m.run_to_next_output(), Ok(None));
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[test]
+    fn zero_replaces_any_tile() {
+        let program = vec![Zero(Direct(5)), CopyFrom(Direct(5)), Outbox, Zero(Indirect(0)), CopyFrom(Direct(5)), Outbox];
+
+        for &start in &[None, Some(Tile::num(42)), Some(Tile::Letter('a'))] {
+            let mut registers: Registers = vec![(0, Tile::num(5))].into_iter().collect();
+            registers.extend(start.map(|t| (5, t)));
+
+            let mut m = Machine::new(program.clone(), vec![], registers);
+            assert_eq!(m.run(), Ok(()));
+            assert_eq!(m.output(), &[Tile::num(0), Tile::num(0)]);
+            assert_eq!(m.registers().get(&5), Some(&Tile::num(0)));
+        }
+    }
+
+    #[cfg(feature = "extended-ops")]
+    fn jump_indirect_through(target: Option<Tile>) -> Result<usize, Error> {
+        let program = vec![JumpIndirect(Direct(0)), Inbox, Outbox, NoOp];
+        let mut m = Machine::new(program, vec![], target.into_iter().map(|t| (0, t)).collect());
+        m.step().map(|_| m.pc())
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[test]
+    fn jump_indirect_in_bounds() {
+        assert_eq!(jump_indirect_through(Some(Tile::num(0))), Ok(0));
+        assert_eq!(jump_indirect_through(Some(Tile::num(2))), Ok(2));
+        assert_eq!(jump_indirect_through(Some(Tile::num(3))), Ok(3));
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[test]
+    fn jump_indirect_out_of_bounds() {
+        assert_eq!(jump_indirect_through(Some(Tile::num(-1))), Err(Error::InvalidJumpTarget));
+        assert_eq!(jump_indirect_through(Some(Tile::Letter('a'))), Err(Error::InvalidJumpTarget));
+        assert_eq!(jump_indirect_through(Some(Tile::num(4))), Err(Error::InvalidJumpTarget));
+        assert_eq!(jump_indirect_through(Some(Tile::num(999))), Err(Error::InvalidJumpTarget));
+        assert_eq!(jump_indirect_through(None), Err(Error::JumpIndirectNil));
+    }
+
+    #[cfg(feature = "assertions")]
+    #[test]
+    fn assert_accumulator_passes() {
+        let mut m = Machine::new(vec![Inbox, AssertAccumulator(3), Outbox], vec![Tile::num(3)], Registers::new());
+        assert_eq!(m.run(), Ok(()));
+        assert_eq!(m.output(), &[Tile::num(3)]);
+    }
+
+    #[cfg(feature = "assertions")]
+    #[test]
+    fn assert_accumulator_fails() {
+        let assert_three = |hand| hand_after(AssertAccumulator(3), hand, None);
+
+        assert_eq!(assert_three(Some(Tile::num(4))), Err(Error::AssertionFailed));
+        assert_eq!(assert_three(Some(Tile::Letter('c'))), Err(Error::AssertionFailed));
+        assert_eq!(assert_three(None), Err(Error::AssertionFailed));
+    }
+}
@@ -1,29 +1,28 @@
-#[macro_use]
-extern crate peresil;
+extern crate human_resource_machine;
 extern crate rustc_serialize;
 extern crate docopt;
 
-mod parser;
-mod compiler;
-mod machine;
-mod level;
-
-use std::fs::File;
+use std::fs::{self, File};
+use std::io;
 use std::io::prelude::*;
+use std::process;
 
-use parser::Parser;
-use compiler::Program;
-use machine::Machine;
+use human_resource_machine::{parser, compiler, machine, level};
+use human_resource_machine::parser::Parser;
+use human_resource_machine::compiler::Program;
+use human_resource_machine::machine::{Machine, GradeReport, render_output};
+use human_resource_machine::level::Level;
 
 use docopt::Docopt;
 
-#[derive(Debug, Copy, Clone)]
-pub enum Register {
-    Direct(u8),
-    Indirect(u8),
+fn report_parsing_error(s: &str, failure: &parser::ParseFailure) {
+    println!("Error occured while parsing at line {}, column {}:", failure.line, failure.column);
+    print_source_location(s, failure.offset);
+    let expected: Vec<_> = failure.errors.iter().map(|e| e.to_string()).collect();
+    println!("{}", expected.join(" or "));
 }
 
-fn report_parsing_error(s: &str, offset: usize, errors: &[parser::Error]) {
+fn print_source_location(s: &str, offset: usize) {
     let upto = &s[..offset];
     let leading_nl = upto.rfind("\n").map(|x| x + 1).unwrap_or(0);
     let after = &s[offset..];
@@ -32,24 +31,237 @@ fn report_parsing_error(s: &str, offset: usize, errors: &[parser::Error]) {
     let line = &s[leading_nl..trailing_nl];
     let inner_offset = offset - leading_nl;
 
-    println!("Error occured while parsing:");
     println!("{}", line);
-    for _ in 0..inner_offset { print!(" ") }
+    for _ in line[..inner_offset].chars() { print!(" ") }
     println!("^");
-    println!("{:?}", errors);
 }
 
+fn report_difference(report: &GradeReport) {
+    if report.expected_len != report.actual_len {
+        println!("Expected {} tiles, got {}", report.expected_len, report.actual_len);
+    }
+
+    let diff = match report.difference {
+        Some(ref diff) => diff,
+        None => return,
+    };
+
+    let describe = |t: Option<machine::Tile>| match t {
+        Some(t) => format!("'{}'", t),
+        None => "nothing".to_string(),
+    };
+
+    if diff.is_type_mismatch() {
+        let kind = |t: Option<machine::Tile>| match t {
+            Some(machine::Tile::Number(..)) => "number",
+            _ => "letter",
+        };
+        println!("Type mismatch at position {}: expected {} {}, got {} {}",
+                 diff.position,
+                 kind(diff.expected), describe(diff.expected),
+                 kind(diff.actual), describe(diff.actual));
+    } else {
+        println!("First difference at position {}: expected {}, got {}",
+                 diff.position, describe(diff.expected), describe(diff.actual));
+    }
+    println!("Expected: {}", render_output(&diff.expected_context));
+    println!("Got:      {}", render_output(&diff.actual_context));
+}
+
+// `pc` is where the machine stopped, which is already past the
+// instruction that failed
+fn report_runtime_error(s: &str, p: &Program, e: &machine::Error, pc: usize, accumulator: Option<machine::Tile>) -> ! {
+    println!("Program failed");
+    println!("{}", e);
+    if let Some(span) = pc.checked_sub(1).and_then(|pc| p.span(pc)) {
+        let (line, _) = parser::line_column(s, span.start);
+        println!("At source line {}:", line);
+        print_source_location(s, span.start);
+    }
+    match accumulator {
+        Some(t) => println!("Accumulator: {}", t),
+        None => println!("Accumulator: empty"),
+    }
+    process::exit(EXIT_RUNTIME);
+}
+
+// Compiles the program once and runs it against every case of every
+// built-in level, giving whether each level passed.
+fn test_all_levels(program_src: &str, options: parser::Options) -> Result<Vec<(usize, bool)>, compiler::Error<parser::ParseFailure>> {
+    let p = try!(Program::compile_spanned(Parser::new_with_options(program_src, options).spanned()));
+
+    Ok(level::builtin_levels().into_iter().map(|(number, level)| {
+        (number, level.validate(&p).passed)
+    }).collect())
+}
+
+// Runs every `.hrm` file in the directory against the level. A file
+// that can't be read or compiled is reported and the rest carry on.
+fn run_batch(level: &Level, dir: &str, options: parser::Options) -> io::Result<()> {
+    let mut paths: Vec<_> = try!(fs::read_dir(dir))
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.extension().map_or(false, |ext| ext == "hrm"))
+        .collect();
+    paths.sort();
+
+    println!("{:<30} {:<8} {:>8} {:>12}", "File", "Result", "Runtime", "Instructions");
+
+    for path in paths {
+        let name = path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+
+        let mut s = String::new();
+        if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut s)) {
+            println!("{:<30} could not read: {}", name, e);
+            continue;
+        }
+
+        let p = match Program::compile(Parser::new_with_options(&s, options)) {
+            Ok(p) => p,
+            Err(e) => {
+                println!("{:<30} could not compile: {}", name, e);
+                continue;
+            }
+        };
+        let result = level.validate(&p);
+
+        println!("{:<30} {:<8} {:>8} {:>12}",
+                 name, if result.passed { "pass" } else { "fail" }, result.stats.runtime, result.stats_len);
+    }
+
+    Ok(())
+}
+
+fn load_level(path: &str) -> Result<Level, level::Error> {
+    if path == "-" {
+        Level::from_json_reader(&mut io::stdin())
+    } else {
+        Level::from_json_file(path)
+    }
+}
+
+// Exits if there is no level number or no built-in level with it
+fn builtin_level(number: Option<usize>) -> (usize, Level) {
+    let number = match number {
+        Some(number) => number,
+        None => {
+            println!("A level number is required");
+            process::exit(EXIT_USAGE);
+        }
+    };
+    match level::builtin(number) {
+        Some(level) => (number, level),
+        None => {
+            println!("Unknown level {}", number);
+            process::exit(EXIT_USAGE);
+        }
+    }
+}
+
+// Returns `None` if the user quit before the program finished
+fn run_interactive(m: &mut Machine) -> Option<Result<(), machine::Error>> {
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        match m.current_instruction() {
+            Some(i) => print!("pc {} {}> ", m.pc(), i),
+            None => print!("pc {}> ", m.pc()),
+        }
+        io::stdout().flush().expect("Could not write prompt");
+
+        match lines.next() {
+            Some(Ok(ref line)) if line.trim() == "q" => return None,
+            Some(Ok(..)) => {},
+            _ => return None,
+        }
+
+        match m.step_traced() {
+            Ok(entry) => {
+                println!("{:>4}: {}", entry.pc, entry.instruction);
+                match entry.accumulator_after {
+                    Some(t) => println!("      Accumulator: {}", t),
+                    None => println!("      Accumulator: empty"),
+                }
+                if let Some((r, t)) = entry.register_written {
+                    println!("      Register {}: {}", r, t);
+                }
+                if let Some(t) = entry.output_written {
+                    println!("      Output: {}", t);
+                }
+            },
+            Err(machine::Error::EndOfProgram) |
+            Err(machine::Error::InboxEmpty) => return Some(Ok(())),
+            Err(e) => return Some(Err(e)),
+        }
+    }
+}
+
+// Exit statuses, as documented in the usage
+const EXIT_USAGE: i32 = 1;
+const EXIT_PARSE: i32 = 2;
+const EXIT_COMPILE: i32 = 3;
+const EXIT_RUNTIME: i32 = 4;
+const EXIT_MISMATCH: i32 = 5;
+
 const USAGE: &'static str = "
 Human Resource Machine simulator.
 
 Usage:
-  human-resource-machine <level> <file>
+  human-resource-machine [options] <level> <file>
+  human-resource-machine [options] --level-file=<path> <file>
+  human-resource-machine [options] --check <file>
+  human-resource-machine [options] --stats <file>
+  human-resource-machine [options] --dump-ir <file>
+  human-resource-machine [options] --dot <file>
+  human-resource-machine [options] --all-levels <file>
+  human-resource-machine [options] batch <level> <dir>
+  human-resource-machine --list-levels
+
+A <file> or <path> of - is read from standard input.
+
+Exit status:
+  0  The program ran and produced the expected output.
+  1  The arguments, level, or files could not be used.
+  2  The program could not be parsed.
+  3  The program could not be compiled.
+  4  The program failed while running.
+  5  The program's output did not match.
+
+Options:
+  --all-errors         Report every parse error instead of just the first.
+  --all-levels         Run the program against every built-in level.
+  --check              Only parse and compile the program.
+  --dot                Show the control flow as a Graphviz graph.
+  --dump-ir            Show the compiled instructions without running them.
+  --ignore-case        Accept instructions written in any case.
+  --interactive        Run one instruction each time Enter is pressed.
+  --level-file=<path>  Load the level from a JSON file.
+  --list-levels        Show the built-in levels.
+  --profile            Show how many times each instruction ran.
+  --seed=<n>           Use a random input for a built-in level.
+  --shorthand          Accept short names like CPF and JZ for instructions.
+  --stats              Show the size of the program without running it.
 ";
 
 #[derive(Debug, Clone, RustcDecodable)]
 struct Args {
-    arg_level: usize,
+    cmd_batch: bool,
+    arg_level: Option<usize>,
     arg_file: String,
+    arg_dir: String,
+    flag_level_file: Option<String>,
+    flag_stats: bool,
+    flag_check: bool,
+    flag_all_errors: bool,
+    flag_all_levels: bool,
+    flag_dump_ir: bool,
+    flag_dot: bool,
+    flag_ignore_case: bool,
+    flag_interactive: bool,
+    flag_list_levels: bool,
+    flag_profile: bool,
+    flag_seed: Option<u64>,
+    flag_shorthand: bool,
 }
 
 fn main() {
@@ -57,60 +269,231 @@ fn main() {
         .and_then(|d| d.decode())
         .unwrap_or_else(|e| e.exit());
 
-    let mut f = File::open(args.arg_file).expect("Could not open source file");
+    if args.flag_list_levels {
+        for (number, level) in level::builtin_levels() {
+            println!("{:>3} {}", number, level.name);
+        }
+        return;
+    }
+
+    if args.cmd_batch {
+        let (_, level) = builtin_level(args.arg_level);
+        let options = parser::Options { case_insensitive: args.flag_ignore_case, shorthand: args.flag_shorthand };
+        if let Err(e) = run_batch(&level, &args.arg_dir, options) {
+            println!("Could not read {}: {}", args.arg_dir, e);
+            process::exit(EXIT_USAGE);
+        }
+        return;
+    }
+
+    if args.arg_file == "-" && args.flag_level_file.as_ref().map_or(false, |p| p == "-") {
+        println!("Only one of the program and level can be read from standard input");
+        process::exit(EXIT_USAGE);
+    }
+    if args.flag_interactive && (args.arg_file == "-" || args.flag_level_file.as_ref().map_or(false, |p| p == "-")) {
+        println!("Interactive mode needs standard input for commands");
+        process::exit(EXIT_USAGE);
+    }
 
     let mut s = String::new();
-    f.read_to_string(&mut s).expect("Could not read source file");
+    let read = if args.arg_file == "-" {
+        io::stdin().read_to_string(&mut s)
+    } else {
+        File::open(&args.arg_file).and_then(|mut f| f.read_to_string(&mut s))
+    };
+    if let Err(e) = read {
+        println!("Could not read {}: {}", args.arg_file, e);
+        process::exit(EXIT_USAGE);
+    }
 
-    let t = Parser::new(&s);
+    let options = parser::Options { case_insensitive: args.flag_ignore_case, shorthand: args.flag_shorthand };
+
+    if args.flag_all_levels {
+        match test_all_levels(&s, options) {
+            Ok(results) => {
+                for (number, passed) in results {
+                    println!("{:>3} {}", number, if passed { "passed" } else { "failed" });
+                }
+            },
+            Err(compiler::Error::ParserError(failure)) => {
+                report_parsing_error(&s, &failure);
+                process::exit(EXIT_PARSE);
+            },
+            Err(e) => {
+                println!("Error occurred while compiling: {}", e);
+                process::exit(EXIT_COMPILE);
+            },
+        }
+        return;
+    }
+
+    let t = Parser::new_with_options(&s, options);
+
+    let compiled = if args.flag_all_errors {
+        match t.spanned().collect_all() {
+            Ok(tokens) => Program::compile_spanned(tokens.into_iter().map(Ok)),
+            Err(failures) => {
+                for failure in &failures {
+                    report_parsing_error(&s, failure);
+                }
+                process::exit(EXIT_PARSE);
+            },
+        }
+    } else {
+        Program::compile_spanned(t.spanned())
+    };
 
-    let p = match Program::compile(t) {
+    let p = match compiled {
         Ok(p) => p,
-        Err(compiler::Error::ParserError((offset, errors))) => {
-            report_parsing_error(&s, offset, &errors);
-            return;
+        Err(compiler::Error::ParserError(failure)) => {
+            report_parsing_error(&s, &failure);
+            process::exit(EXIT_PARSE);
+        },
+        Err(compiler::Error::UndefinedLabel { label, span, .. }) => {
+            println!("Error occurred while compiling: undefined label {}", label);
+            if let Some(span) = span {
+                print_source_location(&s, span.start);
+            }
+            process::exit(EXIT_COMPILE);
         },
         Err(e) =>  {
-            println!("Error occurred while compiling: {:?}", e);
-            return;
+            println!("Error occurred while compiling: {}", e);
+            process::exit(EXIT_COMPILE);
         },
     };
+
+    if args.flag_check {
+        println!("No errors found");
+        return;
+    }
     let program_length = p.stats_len();
 
-    let (input, registers, output) = match args.arg_level {
-        1 => level::level_1(),
-        2 => level::level_2(),
-        3 => level::level_3(),
-        4 => level::level_4(),
-        35 => level::level_35(),
-        36 => level::level_36(),
-        37 => level::level_37(),
-        38 => level::level_38(),
-        _ => panic!("Unknown level {}", args.arg_level),
-    };
-    let mut m = Machine::new(p, input, registers);
-
-    match m.run() {
-        Ok(..) => {
-            let actual_output = m.output();
-            println!("Program completed");
-            if actual_output == &output {
-                let stats = m.stats();
-
-                println!("Output matched!");
-                println!("==========");
-                println!("Instructions {}", program_length);
-                println!("Runtime      {}", stats.runtime);
-                println!("Memory Usage {}", stats.memory_usage);
-            } else {
-                println!("Output did not match");
-                println!("Expected: {:?}", output);
-                println!("Got:      {:?}", actual_output);
+    if args.flag_dump_ir {
+        for (i, instruction) in p.instructions().iter().enumerate() {
+            println!("{}: {:?}", i, instruction);
+        }
+        return;
+    }
+
+    if args.flag_dot {
+        print!("{}", p.to_dot());
+        return;
+    }
+
+    if args.flag_stats {
+        println!("Instructions {}", program_length);
+        println!("With labels  {}", p.len());
+        println!("Registers    {}", p.registers_used().len());
+        for (mnemonic, count) in p.instruction_histogram() {
+            println!("  {:<10} {}", mnemonic, count);
+        }
+        return;
+    }
+
+    let level = match args.flag_level_file {
+        Some(ref path) => match load_level(path) {
+            Ok(level) => level,
+            Err(e) => {
+                println!("Error occurred while loading level: {}", e);
+                process::exit(EXIT_USAGE);
+            }
+        },
+        None => {
+            let (number, level) = builtin_level(args.arg_level);
+            match args.flag_seed {
+                None => level,
+                Some(seed) => match Level::randomized(number, seed) {
+                    Some(level) => level,
+                    None => {
+                        println!("Level {} has no random inputs", number);
+                        process::exit(EXIT_USAGE);
+                    }
+                },
             }
         },
-        Err(e) => {
-            println!("Program failed");
-            println!("{:?}", e);
+    };
+    // Interactive and profiled runs show the level's own input as it
+    // runs, then the program is graded like any other
+    if args.flag_interactive || args.flag_profile {
+        let mut m = Machine::new(p.clone(), level.input.clone(), level.registers.clone());
+        m.set_floor_size(level.floor_size);
+        m.set_tile_kinds(level.tile_kinds.clone());
+        m.set_max_output(level.max_output());
+
+        let result = if args.flag_interactive {
+            match run_interactive(&mut m) {
+                Some(result) => result,
+                None => return,
+            }
+        } else {
+            let max_steps = level.constraints().max_steps.unwrap_or(usize::MAX);
+            let (result, counts) = m.run_profiled_with_limit(max_steps);
+            println!("Steps {}", m.steps());
+            for (pc, count) in counts {
+                println!("{:>4}: {:>6}  {:?}", pc, count, p.instructions()[pc]);
+            }
+            result
+        };
+
+        if let Err(e) = result {
+            report_runtime_error(&s, &p, &e, m.pc(), m.accumulator());
+        }
+    }
+
+    let result = level.validate(&p);
+
+    if let Some(ref e) = result.error {
+        if result.case > 0 {
+            println!("Additional case {} failed", result.case);
+        }
+        report_runtime_error(&s, &p, e, result.pc, result.accumulator);
+    }
+
+    println!("Program completed");
+    if !result.passed {
+        if result.case > 0 {
+            println!("Additional case {} failed", result.case);
+        } else {
+            println!("Output did not match");
         }
+        report_difference(&result.report);
+        process::exit(EXIT_MISMATCH);
+    }
+
+    println!("Output matched!");
+    println!("==========");
+    println!("Level        {}", level.name);
+    println!("Instructions {}", result.stats_len);
+    println!("Runtime      {}", result.stats.runtime);
+    println!("Memory Usage {}", result.stats.memory_usage);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Solves every level that only copies the inbox to the outbox
+    const COPY: &'static str = "a:\nINBOX\nOUTBOX\nJUMP a";
+
+    fn passed(results: &[(usize, bool)], level: usize) -> bool {
+        results.iter().any(|&(number, passed)| number == level && passed)
+    }
+
+    #[test]
+    fn copy_solution_passes_only_the_copying_levels() {
+        let results = test_all_levels(COPY, parser::Options::default()).expect("Solution does not compile");
+        assert!(passed(&results, 1));
+        assert!(passed(&results, 2));
+        assert!(!passed(&results, 3));
+    }
+
+    #[test]
+    fn all_levels_uses_the_parser_options() {
+        let source = COPY.to_lowercase();
+        assert!(test_all_levels(&source, parser::Options::default()).is_err());
+
+        let options = parser::Options { case_insensitive: true, ..parser::Options::default() };
+        let results = test_all_levels(&source, options).expect("Solution does not compile");
+        assert!(passed(&results, 1));
     }
 }
@@ -1,3 +1,6 @@
+use std::error;
+use std::fmt;
+
 use peresil::{ParseMaster, StringPoint, Progress, Status, Recoverable};
 
 use super::Register;
@@ -16,6 +19,7 @@ pub enum Error {
     ExpectedIndirectRegister,
     ExpectedIndirectRegisterEnd,
     ExpectedRegisterValue,
+    RegisterValueOutOfRange,
     ExpectedLabelDefinition,
     ExpectedLabelValue,
     ExpectedJump,
@@ -32,36 +36,226 @@ pub enum Error {
     ExpectedRegisterLabelDefinitionData,
     ExpectedRegisterLabelDefinitionEnd,
     ExpectedColon,
+    ExpectedLineComment,
+    #[cfg(feature = "extended-ops")]
+    ExpectedZero,
+    #[cfg(feature = "extended-ops")]
+    ExpectedJumpIndirect,
+    #[cfg(feature = "assertions")]
+    ExpectedAssertAccumulator,
+    #[cfg(feature = "assertions")]
+    ExpectedNumber,
 }
 
 impl Recoverable for Error {
     fn recoverable(&self) -> bool { true }
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        let expected = match *self {
+            ExpectedHeader => "the program header",
+            ExpectedInbox => "INBOX",
+            ExpectedOutbox => "OUTBOX",
+            ExpectedCopyFrom => "COPYFROM",
+            ExpectedCopyTo => "COPYTO",
+            ExpectedBumpUp => "BUMPUP",
+            ExpectedBumpDown => "BUMPDN",
+            ExpectedAdd => "ADD",
+            ExpectedSub => "SUB",
+            ExpectedIndirectRegister => "an indirect register",
+            ExpectedIndirectRegisterEnd => "`]` to end an indirect register",
+            ExpectedRegisterValue => "a register number",
+            RegisterValueOutOfRange => "a register number no larger than 255",
+            ExpectedLabelDefinition => "a label",
+            ExpectedLabelValue => "a label name",
+            ExpectedJump => "JUMP",
+            ExpectedJumpIfZero => "JUMPZ",
+            ExpectedJumpIfNegative => "JUMPN",
+            ExpectedWhiteSpace => "whitespace",
+            ExpectedComment => "COMMENT",
+            ExpectedCommentId => "a comment number",
+            ExpectedCommentDefinition => "DEFINE COMMENT",
+            ExpectedCommentDefinitionData => "comment data",
+            ExpectedCommentDefinitionEnd => "`;` to end the comment data",
+            ExpectedRegisterLabelDefinition => "DEFINE LABEL",
+            ExpectedRegisterLabelId => "a register number for the label",
+            ExpectedRegisterLabelDefinitionData => "label data",
+            ExpectedRegisterLabelDefinitionEnd => "`;` to end the label data",
+            ExpectedColon => "`:` after the label",
+            ExpectedLineComment => "`--`",
+            #[cfg(feature = "extended-ops")]
+            ExpectedZero => "ZERO",
+            #[cfg(feature = "extended-ops")]
+            ExpectedJumpIndirect => "JUMPI",
+            #[cfg(feature = "assertions")]
+            ExpectedAssertAccumulator => "ASSERTACC",
+            #[cfg(feature = "assertions")]
+            ExpectedNumber => "a number",
+        };
+
+        write!(f, "expected {}", expected)
+    }
+}
+
+impl error::Error for Error {}
+
+// Every alternative that was tried at the furthest point parsing reached
+#[derive(Debug, Clone)]
+pub struct ParseFailure {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+    pub errors: Vec<Error>,
+}
+
+impl fmt::Display for ParseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let expected: Vec<_> = self.errors.iter().map(|e| e.to_string()).collect();
+        write!(f, "at line {}, column {}: {}", self.line, self.column, expected.join(" or "))
+    }
+}
+
+impl error::Error for ParseFailure {}
+
 type ZPM<'a> = ParseMaster<StringPoint<'a>, Error>;
 type ZPR<'a, T> = Progress<StringPoint<'a>, T, Error>;
 
+// `case_insensitive` allows the header, every instruction name
+// (`INBOX`, `COPYFROM`, `JUMPZ`, ...), `COMMENT`, `DEFINE COMMENT` and
+// `DEFINE LABEL` to be written in any case. Labels are unaffected and
+// must remain lowercase.
+//
+// `shorthand` also accepts `CPF`, `CPT`, `BU`, `BD`, `JZ` and `JN` for
+// `COPYFROM`, `COPYTO`, `BUMPUP`, `BUMPDN`, `JUMPZ` and `JUMPN`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Options {
+    pub case_insensitive: bool,
+    pub shorthand: bool,
+}
+
 #[derive(Debug, Copy, Clone)]
 pub struct Parser<'a> {
+    source: &'a str,
     point: StringPoint<'a>,
+    options: Options,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(s: &str) -> Parser {
+        Parser::new_with_options(s, Options::default())
+    }
+
+    pub fn new_with_options(s: &str, options: Options) -> Parser {
         Parser {
+            source: s,
             point: StringPoint::new(s),
+            options: options,
         }
     }
+
+    pub fn offset(&self) -> usize {
+        self.point.offset
+    }
+
+    pub fn line_column(&self, offset: usize) -> (usize, usize) {
+        line_column(self.source, offset)
+    }
+
+    pub fn spanned(self) -> Spanned<'a> {
+        Spanned { parser: self }
+    }
+
+    // Skips the token that just failed to parse. That is the rest of
+    // the line it started on, unless it is a `DEFINE` block, whose data
+    // spans lines up to its `;`.
+    fn skip_failed_token(&mut self) {
+        let terminator = match consume_keyword(self.point, "DEFINE", self.options).status {
+            Status::Success(..) => ';',
+            Status::Failure(..) => '\n',
+        };
+
+        let rest = self.point.s;
+        let len = rest.find(terminator).map_or(rest.len(), |i| i + 1);
+        self.point = self.point.consume_to(Some(len)).point;
+    }
+}
+
+// Gives each token along with where it was in the source
+#[derive(Debug, Copy, Clone)]
+pub struct Spanned<'a> {
+    parser: Parser<'a>,
+}
+
+impl<'a> Spanned<'a> {
+    // Instead of stopping at the first failure, carries on after the
+    // token that failed so that every failure in the source is found
+    pub fn collect_all(mut self) -> Result<Vec<(Token<'a>, Span)>, Vec<ParseFailure>> {
+        let mut tokens = Vec::new();
+        let mut failures = Vec::new();
+
+        while let Some(result) = self.next() {
+            match result {
+                Ok(token) => tokens.push(token),
+                Err(failure) => {
+                    self.parser.skip_failed_token();
+                    failures.push(failure);
+                },
+            }
+        }
+
+        if failures.is_empty() { Ok(tokens) } else { Err(failures) }
+    }
+}
+
+impl<'a> Iterator for Spanned<'a> {
+    type Item = Result<(Token<'a>, Span), ParseFailure>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = self.parser.offset();
+        self.parser.next().map(|r| r.map(|t| {
+            (t, Span { start: start, end: self.parser.offset() })
+        }))
+    }
+}
+
+// Converts a byte offset into a 1-based line and column. Columns count
+// characters, not bytes.
+pub fn line_column(s: &str, offset: usize) -> (usize, usize) {
+    let upto = &s[..offset];
+    let line = upto.matches('\n').count() + 1;
+    let line_start = upto.rfind('\n').map(|x| x + 1).unwrap_or(0);
+    let column = upto[line_start..].chars().count() + 1;
+
+    (line, column)
+}
+
+// A range of byte offsets into the source
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
+// Comment and label definition data is the game's encoded drawing,
+// which usually spans several lines. It is kept exactly as written,
+// newlines included.
 pub type Label<'a> = &'a str;
 pub type CommentId<'a> = &'a str;
 pub type CommentData<'a> = &'a str;
+pub type RegisterLabelId<'a> = &'a str;
+pub type RegisterLabelData<'a> = &'a str;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Token<'a> {
     Header,
     Inbox,
+    // `INBOX n` takes from the inbox numbered `n`. Plain `INBOX` is
+    // the same as `INBOX 0`.
+    InboxFrom(u8),
     Outbox,
     CopyFrom(Register),
     CopyTo(Register),
@@ -69,60 +263,108 @@ pub enum Token<'a> {
     BumpDown(Register), // name?
     Add(Register),
     Sub(Register),
+    #[cfg(feature = "extended-ops")]
+    Zero(Register),
+    #[cfg(feature = "extended-ops")]
+    JumpIndirect(Register),
+    #[cfg(feature = "assertions")]
+    AssertAccumulator(i16),
     LabelDefinition(Label<'a>),
     Jump(Label<'a>),
     JumpIfZero(Label<'a>),
     JumpIfNegative(Label<'a>),
     Comment(CommentId<'a>),
     CommentDefinition(CommentId<'a>, CommentData<'a>),
+    RegisterLabelDefinition(RegisterLabelId<'a>, RegisterLabelData<'a>),
+    // The text after `--`, up to the end of the line
+    LineComment(&'a str),
     Whitespace(&'a str),
 }
 
-fn parse_header<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    pt.consume_literal("-- HUMAN RESOURCE MACHINE PROGRAM --")
+fn parse_header<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    consume_keyword(pt, "-- HUMAN RESOURCE MACHINE PROGRAM --", opts)
         .map(|_| Token::Header)
         .map_err(|_| Error::ExpectedHeader)
 }
 
-fn parse_inbox<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    pt.consume_literal("INBOX")
+fn parse_inbox<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    consume_keyword(pt, "INBOX", opts)
         .map(|_| Token::Inbox)
         .map_err(|_| Error::ExpectedInbox)
 }
 
-fn parse_outbox<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    pt.consume_literal("OUTBOX")
+fn parse_inbox_from<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    let (pt, _) = try_parse!(consume_keyword(pt, "INBOX", opts).map_err(|_| Error::ExpectedInbox));
+    let (pt, _) = try_parse!{parse_whitespace(pm, pt)};
+    let (pt, n) = try_parse!{parse_register_value(pm, pt)};
+
+    Progress::success(pt, Token::InboxFrom(n))
+}
+
+fn parse_outbox<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    consume_keyword(pt, "OUTBOX", opts)
         .map(|_| Token::Outbox)
         .map_err(|_| Error::ExpectedOutbox)
 }
 
-fn parse_copy_from<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_single_register_instruction(pm, pt, "COPYFROM", Token::CopyFrom, Error::ExpectedCopyFrom)
+fn parse_copy_from<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "COPYFROM", Token::CopyFrom, Error::ExpectedCopyFrom)
+}
+
+fn parse_copy_to<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "COPYTO", Token::CopyTo, Error::ExpectedCopyTo)
 }
 
-fn parse_copy_to<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_single_register_instruction(pm, pt, "COPYTO", Token::CopyTo, Error::ExpectedCopyTo)
+fn parse_bump_up<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "BUMPUP", Token::BumpUp, Error::ExpectedBumpUp)
 }
 
-fn parse_bump_up<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_single_register_instruction(pm, pt, "BUMPUP", Token::BumpUp, Error::ExpectedBumpUp)
+fn parse_bump_down<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "BUMPDN", Token::BumpDown, Error::ExpectedBumpDown)
 }
 
-fn parse_bump_down<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_single_register_instruction(pm, pt, "BUMPDN", Token::BumpDown, Error::ExpectedBumpDown)
+fn parse_add<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "ADD", Token::Add, Error::ExpectedAdd)
 }
 
-fn parse_add<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_single_register_instruction(pm, pt, "ADD", Token::Add, Error::ExpectedAdd)
+fn parse_sub<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "SUB", Token::Sub, Error::ExpectedSub)
 }
 
-fn parse_sub<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_single_register_instruction(pm, pt, "SUB", Token::Sub, Error::ExpectedSub)
+#[cfg(feature = "extended-ops")]
+fn parse_zero<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "ZERO", Token::Zero, Error::ExpectedZero)
+}
+
+#[cfg(feature = "extended-ops")]
+fn parse_jump_indirect<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_single_register_instruction(pm, pt, opts, "JUMPI", Token::JumpIndirect, Error::ExpectedJumpIndirect)
+}
+
+#[cfg(feature = "assertions")]
+fn parse_assert_accumulator<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    let (pt, _) = try_parse!{
+        consume_keyword(pt, "ASSERTACC", opts).map_err(|_| Error::ExpectedAssertAccumulator)
+    };
+    let (pt, _) = try_parse!{parse_whitespace(pm, pt)};
+
+    let start = pt;
+    let digits_start = pt.consume_literal("-").point;
+    let (pt, _) = try_parse!{
+        string_point_consume_while(digits_start, |c| c.is_digit(10))
+            .map_err(|_| Error::ExpectedNumber)
+    };
+
+    match start.s[..pt.offset - start.offset].parse() {
+        Ok(n) => Progress::success(pt, Token::AssertAccumulator(n)),
+        Err(..) => Progress::failure(start, Error::ExpectedNumber),
+    }
 }
 
 fn parse_single_register_instruction<'a, F>(
     pm: &mut ZPM<'a>,
     pt: StringPoint<'a>,
+    opts: Options,
     instruction_name: &str,
     token_creator: F,
     error_kind: Error
@@ -130,7 +372,7 @@ fn parse_single_register_instruction<'a, F>(
     -> ZPR<'a, Token<'a>>
     where F: FnOnce(Register) -> Token<'a>
 {
-    let (pt, _) = try_parse!(pt.consume_literal(instruction_name).map_err(|_| error_kind));
+    let (pt, _) = try_parse!(consume_keyword(pt, instruction_name, opts).map_err(|_| error_kind));
     let (pt, _) = try_parse!{parse_whitespace(pm, pt)};
     let (pt, reg) = try_parse!{parse_register(pm, pt)};
 
@@ -157,9 +399,21 @@ fn parse_register_indirect<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a,
 }
 
 fn parse_register_value<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, u8> {
-    string_point_consume_while(pt, |c| c.is_digit(10))
-        .map(|v| v.parse().unwrap()) // unrecoverable error
-        .map_err(|_| Error::ExpectedRegisterValue)
+    let start = pt;
+    let (pt, v) = try_parse!{
+        string_point_consume_while(pt, |c| c.is_digit(10))
+            .map_err(|_| Error::ExpectedRegisterValue)
+    };
+
+    // Matching nothing at the end of the input still succeeds
+    if v.is_empty() {
+        return Progress::failure(start, Error::ExpectedRegisterValue);
+    }
+
+    match v.parse() {
+        Ok(v) => Progress::success(pt, v),
+        Err(..) => Progress::failure(start, Error::RegisterValueOutOfRange),
+    }
 }
 
 fn parse_label_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
@@ -177,21 +431,33 @@ fn parse_label_value<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, &'a st
         .map_err(|_| Error::ExpectedLabelValue)
 }
 
-fn parse_jump<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_jump_instruction(pm, pt, "JUMP", Token::Jump, Error::ExpectedJump)
+fn parse_jump<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_jump_instruction(pm, pt, opts, "JUMP", Token::Jump, Error::ExpectedJump)
+}
+
+fn parse_jump_if_zero<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_jump_instruction(pm, pt, opts, "JUMPZ", Token::JumpIfZero, Error::ExpectedJumpIfZero)
 }
 
-fn parse_jump_if_zero<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_jump_instruction(pm, pt, "JUMPZ", Token::JumpIfZero, Error::ExpectedJumpIfZero)
+fn parse_jump_if_negative<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    parse_jump_instruction(pm, pt, opts, "JUMPN", Token::JumpIfNegative, Error::ExpectedJumpIfNegative)
 }
 
-fn parse_jump_if_negative<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
-    parse_jump_instruction(pm, pt, "JUMPN", Token::JumpIfNegative, Error::ExpectedJumpIfNegative)
+fn parse_shorthand<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
+    pm.alternate()
+        .one(|pm| parse_single_register_instruction(pm, pt, opts, "CPF", Token::CopyFrom, Error::ExpectedCopyFrom))
+        .one(|pm| parse_single_register_instruction(pm, pt, opts, "CPT", Token::CopyTo, Error::ExpectedCopyTo))
+        .one(|pm| parse_single_register_instruction(pm, pt, opts, "BU", Token::BumpUp, Error::ExpectedBumpUp))
+        .one(|pm| parse_single_register_instruction(pm, pt, opts, "BD", Token::BumpDown, Error::ExpectedBumpDown))
+        .one(|pm| parse_jump_instruction(pm, pt, opts, "JZ", Token::JumpIfZero, Error::ExpectedJumpIfZero))
+        .one(|pm| parse_jump_instruction(pm, pt, opts, "JN", Token::JumpIfNegative, Error::ExpectedJumpIfNegative))
+        .finish()
 }
 
 fn parse_jump_instruction<'a, F>(
     pm: &mut ZPM<'a>,
     pt: StringPoint<'a>,
+    opts: Options,
     instruction_name: &str,
     token_creator: F,
     error_kind: Error
@@ -199,16 +465,16 @@ fn parse_jump_instruction<'a, F>(
     -> ZPR<'a, Token<'a>>
     where F: FnOnce(Label<'a>) -> Token<'a>
 {
-    let (pt, _) = try_parse!(pt.consume_literal(instruction_name).map_err(|_| error_kind));
+    let (pt, _) = try_parse!(consume_keyword(pt, instruction_name, opts).map_err(|_| error_kind));
     let (pt, _) = try_parse!{parse_whitespace(pm, pt)};
     let (pt, lab) = try_parse!{parse_label_value(pm, pt)};
 
     Progress::success(pt, token_creator(lab))
 }
 
-fn parse_comment<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
+fn parse_comment<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
     let (pt, _) = try_parse!(
-        pt.consume_literal("COMMENT")
+        consume_keyword(pt, "COMMENT", opts)
             .map_err(|_| Error::ExpectedComment)
     );
 
@@ -219,9 +485,9 @@ fn parse_comment<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>
     Progress::success(pt, Token::Comment(id))
 }
 
-fn parse_comment_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
+fn parse_comment_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
     let (pt, _) = try_parse!(
-        pt.consume_literal("DEFINE COMMENT")
+        consume_keyword(pt, "DEFINE COMMENT", opts)
             .map_err(|_| Error::ExpectedCommentDefinition)
     );
 
@@ -231,11 +497,16 @@ fn parse_comment_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a
 
     let (pt, _) = try_parse!{parse_whitespace(pm, pt)};
 
+    let data_start = pt;
     let (pt, data) = try_parse!{parse_comment_data(pm, pt)};
 
-    let (pt, _) = try_parse!{
-        pt.consume_literal(";")
-            .map_err(|_| Error::ExpectedCommentDefinitionEnd)
+    // The data runs to the end of the input when there's no `;`, so
+    // point at where it started instead
+    let (pt, _) = match pt.consume_literal(";") {
+        Progress { status: Status::Success(v), point } => (point, v),
+        Progress { status: Status::Failure(..), .. } => {
+            return Progress::failure(data_start, Error::ExpectedCommentDefinitionEnd)
+        },
     };
 
     Progress::success(pt, Token::CommentDefinition(id, data))
@@ -251,9 +522,9 @@ fn parse_comment_data<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, &'a s
         .map_err(|_| Error::ExpectedCommentDefinitionData)
 }
 
-fn parse_register_label_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
+fn parse_register_label_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>, opts: Options) -> ZPR<'a, Token<'a>> {
     let (pt, _) = try_parse!(
-        pt.consume_literal("DEFINE LABEL")
+        consume_keyword(pt, "DEFINE LABEL", opts)
             .map_err(|_| Error::ExpectedRegisterLabelDefinition)
     );
 
@@ -263,14 +534,19 @@ fn parse_register_label_definition<'a>(pm: &mut ZPM<'a>, pt: StringPoint<'a>) ->
 
     let (pt, _) = try_parse!{parse_whitespace(pm, pt)};
 
+    let data_start = pt;
     let (pt, data) = try_parse!{parse_register_label_data(pm, pt)};
 
-    let (pt, _) = try_parse!{
-        pt.consume_literal(";")
-            .map_err(|_| Error::ExpectedRegisterLabelDefinitionEnd)
+    // The data runs to the end of the input when there's no `;`, so
+    // point at where it started instead
+    let (pt, _) = match pt.consume_literal(";") {
+        Progress { status: Status::Success(v), point } => (point, v),
+        Progress { status: Status::Failure(..), .. } => {
+            return Progress::failure(data_start, Error::ExpectedRegisterLabelDefinitionEnd)
+        },
     };
 
-    Progress::success(pt, Token::CommentDefinition(id, data))
+    Progress::success(pt, Token::RegisterLabelDefinition(id, data))
 }
 
 fn parse_register_label_id<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, &'a str> {
@@ -283,12 +559,30 @@ fn parse_register_label_data<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a
         .map_err(|_| Error::ExpectedRegisterLabelDefinitionData)
 }
 
+fn parse_line_comment<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
+    let (pt, _) = try_parse!(pt.consume_literal("--").map_err(|_| Error::ExpectedLineComment));
+
+    let end = pt.s.find('\n').unwrap_or(pt.s.len());
+    pt.consume_to(Some(end))
+        .map(Token::LineComment)
+        .map_err(|_| Error::ExpectedLineComment)
+}
+
 fn parse_whitespace<'a>(_: &mut ZPM<'a>, pt: StringPoint<'a>) -> ZPR<'a, Token<'a>> {
     string_point_consume_while(pt, char::is_whitespace)
         .map(Token::Whitespace)
         .map_err(|_| Error::ExpectedWhiteSpace)
 }
 
+fn consume_keyword<'a>(pt: StringPoint<'a>, keyword: &str, opts: Options) -> Progress<StringPoint<'a>, &'a str, ()> {
+    if !opts.case_insensitive {
+        return pt.consume_literal(keyword);
+    }
+
+    let matched = pt.s.get(..keyword.len()).map_or(false, |s| s.eq_ignore_ascii_case(keyword));
+    pt.consume_to(if matched { Some(keyword.len()) } else { None })
+}
+
 // Duplicated logic - check and pull to peresil?
 fn string_point_consume_while<'a, F>(pt: StringPoint<'a>, predicate: F) -> Progress<StringPoint<'a>, &str, ()>
     where F: Fn(char) -> bool
@@ -303,32 +597,57 @@ fn string_point_consume_while<'a, F>(pt: StringPoint<'a>, predicate: F) -> Progr
 }
 
 impl<'a> Iterator for Parser<'a> {
-    type Item = Result<Token<'a>, (usize, Vec<Error>)>;
+    type Item = Result<Token<'a>, ParseFailure>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let pt = self.point;
+        let opts = self.options;
 
         if pt.s.is_empty() { return None }
 
         let mut pm = ParseMaster::new();
 
-        let tmp = pm.alternate()
-            .one(|pm| parse_header(pm, pt))
-            .one(|pm| parse_inbox(pm, pt))
-            .one(|pm| parse_outbox(pm, pt))
-            .one(|pm| parse_copy_from(pm, pt))
-            .one(|pm| parse_copy_to(pm, pt))
-            .one(|pm| parse_bump_up(pm, pt))
-            .one(|pm| parse_bump_down(pm, pt))
-            .one(|pm| parse_add(pm, pt))
-            .one(|pm| parse_sub(pm, pt))
+        let alt = pm.alternate()
+            .one(|pm| parse_header(pm, pt, opts))
+            // Ignoring case, a label like `inbox:` also starts like an
+            // instruction, so try labels first
             .one(|pm| parse_label_definition(pm, pt))
-            .one(|pm| parse_jump(pm, pt)) // check ordering of jump (prefix-based?)
-            .one(|pm| parse_jump_if_zero(pm, pt))
-            .one(|pm| parse_jump_if_negative(pm, pt))
-            .one(|pm| parse_comment(pm, pt))
-            .one(|pm| parse_comment_definition(pm, pt))
-            .one(|pm| parse_register_label_definition(pm, pt))
+            // `INBOX` is a prefix of `INBOX n`, so try it first
+            .one(|pm| parse_inbox_from(pm, pt, opts))
+            .one(|pm| parse_inbox(pm, pt, opts))
+            .one(|pm| parse_outbox(pm, pt, opts))
+            .one(|pm| parse_copy_from(pm, pt, opts))
+            .one(|pm| parse_copy_to(pm, pt, opts))
+            .one(|pm| parse_bump_up(pm, pt, opts))
+            .one(|pm| parse_bump_down(pm, pt, opts))
+            .one(|pm| parse_add(pm, pt, opts))
+            .one(|pm| parse_sub(pm, pt, opts))
+            // `JUMP` is a prefix of the conditional jumps, so try them first
+            .one(|pm| parse_jump_if_zero(pm, pt, opts))
+            .one(|pm| parse_jump_if_negative(pm, pt, opts))
+            .one(|pm| parse_jump(pm, pt, opts))
+            .one(|pm| parse_comment(pm, pt, opts))
+            .one(|pm| parse_comment_definition(pm, pt, opts))
+            .one(|pm| parse_register_label_definition(pm, pt, opts))
+            // The header also starts with `--`, so it must be tried first
+            .one(|pm| parse_line_comment(pm, pt));
+
+        #[cfg(feature = "extended-ops")]
+        let alt = alt.one(|pm| parse_zero(pm, pt, opts));
+
+        #[cfg(feature = "extended-ops")]
+        let alt = alt.one(|pm| parse_jump_indirect(pm, pt, opts));
+
+        #[cfg(feature = "assertions")]
+        let alt = alt.one(|pm| parse_assert_accumulator(pm, pt, opts));
+
+        let alt = if opts.shorthand {
+            alt.one(|pm| parse_shorthand(pm, pt, opts))
+        } else {
+            alt
+        };
+
+        let tmp = alt
             .one(|pm| parse_whitespace(pm, pt))
             .finish();
 
@@ -338,8 +657,166 @@ impl<'a> Iterator for Parser<'a> {
                 Some(Ok(tok))
             },
             Progress { status: Status::Failure(e), point } => {
-                Some(Err((point.offset, e)))
+                let (line, column) = line_column(self.source, point.offset);
+                Some(Err(ParseFailure {
+                    offset: point.offset,
+                    line: line,
+                    column: column,
+                    errors: e,
+                }))
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::Register;
+
+    fn tokens<'a>(s: &'a str, options: Options) -> Vec<Token<'a>> {
+        Parser::new_with_options(s, options)
+            .map(|t| t.expect("Could not parse"))
+            .filter(|t| match *t {
+                Token::Whitespace(..) => false,
+                _ => true,
+            })
+            .collect()
+    }
+
+    fn ignoring_case() -> Options {
+        Options { case_insensitive: true, ..Options::default() }
+    }
+
+    #[test]
+    fn lowercase_instructions_when_ignoring_case() {
+        assert_eq!(tokens("inbox\ncopyto 3\noutbox", ignoring_case()),
+                   [Token::Inbox, Token::CopyTo(Register::Direct(3)), Token::Outbox]);
+    }
+
+    #[test]
+    fn labels_named_like_instructions_when_ignoring_case() {
+        assert_eq!(tokens("inbox:\nINBOX\njump:\nJUMP inbox\nJUMPZ jump", ignoring_case()),
+                   [Token::LabelDefinition("inbox"), Token::Inbox,
+                    Token::LabelDefinition("jump"), Token::Jump("inbox"),
+                    Token::JumpIfZero("jump")]);
+    }
+
+    #[test]
+    fn conditional_jumps_are_not_a_jump_and_garbage() {
+        assert_eq!(tokens("JUMPZ loop", Options::default()), [Token::JumpIfZero("loop")]);
+        assert_eq!(tokens("JUMPN loop", Options::default()), [Token::JumpIfNegative("loop")]);
+        assert_eq!(tokens("JUMP loop", Options::default()), [Token::Jump("loop")]);
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[test]
+    fn zero() {
+        assert_eq!(tokens("ZERO 5", Options::default()), [Token::Zero(Register::Direct(5))]);
+        assert_eq!(tokens("ZERO [5]", Options::default()), [Token::Zero(Register::Indirect(5))]);
+    }
+
+    #[test]
+    fn register_too_large() {
+        let failure = match Parser::new("COPYFROM 999").next() {
+            Some(Err(failure)) => failure,
+            other => panic!("Expected a parse failure, got {:?}", other),
+        };
+        assert_eq!(failure.offset, "COPYFROM ".len());
+        assert!(failure.errors.iter().any(|e| matches!(*e, Error::RegisterValueOutOfRange)));
+
+        assert_eq!(tokens("COPYFROM 255", Options::default()), [Token::CopyFrom(Register::Direct(255))]);
+    }
+
+    #[test]
+    fn line_comments() {
+        assert_eq!(tokens("INBOX -- take one\n-- on its own\nOUTBOX --", Options::default()),
+                   [Token::Inbox, Token::LineComment(" take one"), Token::LineComment(" on its own"),
+                    Token::Outbox, Token::LineComment("")]);
+    }
+
+    #[test]
+    fn header_is_not_a_line_comment() {
+        assert_eq!(tokens("-- HUMAN RESOURCE MACHINE PROGRAM --\nINBOX", Options::default()),
+                   [Token::Header, Token::Inbox]);
+    }
+
+    #[test]
+    fn definition_data_spanning_lines() {
+        let source = "DEFINE LABEL 3\neJyzYmBg+\nMzIwMDQw\n\nMDcx;\nDEFINE COMMENT 0\neJwTZGBg\nYDg;";
+        assert_eq!(tokens(source, Options::default()),
+                   [Token::RegisterLabelDefinition("3", "eJyzYmBg+\nMzIwMDQw\n\nMDcx"),
+                    Token::CommentDefinition("0", "eJwTZGBg\nYDg")]);
+    }
+
+    #[test]
+    fn definition_data_without_an_end() {
+        let failure = match Parser::new("DEFINE LABEL 3\neJyzYmBg\nMzIw").next() {
+            Some(Err(failure)) => failure,
+            other => panic!("Expected a parse failure, got {:?}", other),
+        };
+        assert_eq!((failure.line, failure.column), (2, 1));
+    }
+
+    fn failure_lines(s: &str) -> Vec<usize> {
+        match Parser::new(s).spanned().collect_all() {
+            Ok(tokens) => panic!("Expected parse failures, got {:?}", tokens),
+            Err(failures) => failures.iter().map(|f| f.line).collect(),
+        }
+    }
+
+    #[test]
+    fn collect_all_carries_on_after_each_line() {
+        assert_eq!(failure_lines("BOGUS\nINBOX\nNONSENSE\nOUTBOX"), [1, 3]);
+    }
+
+    #[test]
+    fn collect_all_with_a_bad_instruction_before_a_definition() {
+        let definition = "DEFINE LABEL 3\neJyzYmBg+\nMzIwMDQw\n\nMDcx;\nOUTBOX";
+
+        assert_eq!(failure_lines(&format!("INBOX\nBOGUS\n{}", definition)), [2]);
+        // The register is looked for on the next line
+        assert_eq!(failure_lines(&format!("INBOX\nCOPYFROM\n{}", definition)), [3]);
+    }
+
+    #[test]
+    fn collect_all_skips_to_the_end_of_a_bad_definition() {
+        assert_eq!(failure_lines("DEFINE LABEL x\neJyzYmBg+\nMzIw;\nBOGUS\nOUTBOX"), [1, 4]);
+    }
+
+    #[test]
+    fn numbered_inbox() {
+        assert_eq!(tokens("INBOX 1\nINBOX\nOUTBOX", Options::default()),
+                   [Token::InboxFrom(1), Token::Inbox, Token::Outbox]);
+    }
+
+    const SHORTHAND: &'static [(&'static str, &'static str)] = &[
+        ("CPF 1", "COPYFROM 1"),
+        ("CPT [2]", "COPYTO [2]"),
+        ("BU 3", "BUMPUP 3"),
+        ("BD [4]", "BUMPDN [4]"),
+        ("JZ a", "JUMPZ a"),
+        ("JN a", "JUMPN a"),
+    ];
+
+    #[test]
+    fn shorthand_is_the_same_as_the_long_form() {
+        let options = Options { shorthand: true, ..Options::default() };
+
+        for &(short, long) in SHORTHAND {
+            assert_eq!(tokens(short, options), tokens(long, Options::default()), "{}", short);
+        }
+    }
+
+    #[test]
+    fn shorthand_rejected_by_default() {
+        for &(short, _) in SHORTHAND {
+            assert!(Parser::new(short).any(|t| t.is_err()), "{}", short);
+        }
+    }
+
+    #[test]
+    fn lowercase_instructions_rejected_by_default() {
+        assert!(Parser::new("inbox").any(|t| t.is_err()));
+    }
+}
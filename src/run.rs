@@ -0,0 +1,254 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use super::parser::ParseFailure;
+use super::compiler::{self, Program};
+use super::machine::{self, Machine, GradeReport};
+use super::level::{self, Level};
+
+#[derive(Debug)]
+pub enum RunError {
+    Io(io::Error),
+    Parse(ParseFailure),
+    Compile(compiler::Error<ParseFailure>),
+    Level(level::Error),
+    Machine(machine::Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::Io(ref e) => write!(f, "could not read the program: {}", e),
+            RunError::Parse(ref e) => write!(f, "could not parse the program {}", e),
+            RunError::Compile(ref e) => write!(f, "could not compile the program: {}", e),
+            RunError::Level(ref e) => e.fmt(f),
+            RunError::Machine(ref e) => write!(f, "the program failed: {}", e),
+        }
+    }
+}
+
+impl error::Error for RunError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RunError::Io(ref e) => Some(e),
+            RunError::Parse(ref e) => Some(e),
+            RunError::Compile(ref e) => Some(e),
+            RunError::Level(ref e) => Some(e),
+            RunError::Machine(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for RunError {
+    fn from(e: io::Error) -> RunError {
+        RunError::Io(e)
+    }
+}
+
+impl From<compiler::Error<ParseFailure>> for RunError {
+    fn from(e: compiler::Error<ParseFailure>) -> RunError {
+        match e {
+            compiler::Error::ParserError(e) => RunError::Parse(e),
+            e => RunError::Compile(e),
+        }
+    }
+}
+
+impl From<level::Error> for RunError {
+    fn from(e: level::Error) -> RunError {
+        RunError::Level(e)
+    }
+}
+
+impl From<machine::Error> for RunError {
+    fn from(e: machine::Error) -> RunError {
+        RunError::Machine(e)
+    }
+}
+
+// Runs the program against every case of the level, giving the report
+// for the first case that doesn't match, or for the level's own input
+// if they all do.
+pub fn run_hrm_file<P, L>(program_path: P, level_json_path: L) -> Result<GradeReport, RunError>
+    where P: AsRef<Path>,
+          L: AsRef<Path>,
+{
+    let mut source = String::new();
+    try!(try!(File::open(program_path)).read_to_string(&mut source));
+
+    let program: Program = try!(source.parse());
+    let level = try!(Level::from_json_file(level_json_path));
+
+    let result = level.validate(&program);
+    match result.error {
+        Some(e) => Err(e.into()),
+        None => Ok(result.report),
+    }
+}
+
+// One row for each step the machine takes, for explaining a solution
+pub fn run_report(machine: Machine) -> String {
+    report(machine, false)
+}
+
+pub fn run_report_markdown(machine: Machine) -> String {
+    report(machine, true)
+}
+
+// Every step is kept, so a program that never finishes has to be cut off
+const REPORT_STEP_LIMIT: usize = 10_000;
+
+fn report(mut machine: Machine, markdown: bool) -> String {
+    let (result, trace) = machine.run_traced_with_limit(REPORT_STEP_LIMIT);
+
+    let mut s = String::new();
+    if markdown {
+        s.push_str("| Step | PC | Instruction | Hand | Changed |\n");
+        s.push_str("|-----:|---:|-------------|------|---------|\n");
+    } else {
+        s.push_str(&format!("{:>5} {:>4}  {:<14} {:<6} {}\n", "Step", "PC", "Instruction", "Hand", "Changed"));
+    }
+
+    for (step, entry) in trace.iter().enumerate() {
+        let instruction = entry.instruction.to_string();
+        let hand = entry.accumulator_after.map_or(String::new(), |t| t.to_string());
+
+        let mut changed = Vec::new();
+        if let Some((r, t)) = entry.register_written {
+            changed.push(format!("register {} = {}", r, t));
+        }
+        if let Some(t) = entry.output_written {
+            changed.push(format!("outbox {}", t));
+        }
+        let changed = changed.join(", ");
+
+        if markdown {
+            s.push_str(&format!("| {} | {} | `{}` | {} | {} |\n", step + 1, entry.pc, instruction, hand, changed));
+        } else {
+            let row = format!("{:>5} {:>4}  {:<14} {:<6} {}", step + 1, entry.pc, instruction, hand, changed);
+            s.push_str(row.trim_end());
+            s.push('\n');
+        }
+    }
+
+    match result {
+        Ok(..) => {},
+        Err(machine::Error::StepLimitExceeded) => {
+            if markdown { s.push('\n') }
+            s.push_str(&format!("Stopped after {} steps\n", trace.len()));
+        },
+        Err(e) => {
+            if markdown { s.push('\n') }
+            s.push_str(&format!("Failed: {}\n", e));
+        },
+    }
+
+    s
+}
+
+// Where a solution placed on each leaderboard, counting from 1. Tied
+// solutions share a place. Solutions that failed have no places.
+#[derive(Debug, Clone)]
+pub struct Ranking {
+    pub name: String,
+    pub stats_len: usize,
+    // Only instructions that count in the game, like `stats_len`
+    pub runtime: usize,
+    pub size_rank: Option<usize>,
+    pub speed_rank: Option<usize>,
+    pub failure: Option<String>,
+}
+
+// Passing solutions come first, smallest first, then the failures in
+// the order they were given
+pub fn rank_solutions(level: usize, sources: &[(String, &str)]) -> Vec<Ranking> {
+    let level = level::builtin(level);
+
+    let mut rankings: Vec<_> = sources.iter().map(|&(ref name, source)| {
+        let mut ranking = Ranking {
+            name: name.clone(),
+            stats_len: 0,
+            runtime: 0,
+            size_rank: None,
+            speed_rank: None,
+            failure: None,
+        };
+
+        let level = match level {
+            Some(ref level) => level,
+            None => {
+                ranking.failure = Some("there is no such level".to_string());
+                return ranking;
+            }
+        };
+
+        let program: Program = match source.parse() {
+            Ok(p) => p,
+            Err(e) => {
+                ranking.failure = Some(RunError::from(e).to_string());
+                return ranking;
+            }
+        };
+
+        let result = level.validate(&program);
+        ranking.stats_len = result.stats_len;
+        ranking.runtime = result.stats.runtime;
+        ranking.failure = match result.error {
+            Some(e) => Some(RunError::from(e).to_string()),
+            None if !result.passed => Some("the output did not match".to_string()),
+            None => None,
+        };
+        ranking
+    }).collect();
+
+    let passed: Vec<_> = rankings.iter().filter(|r| r.failure.is_none()).cloned().collect();
+    let place = |score: usize, score_of: &dyn Fn(&Ranking) -> usize| {
+        1 + passed.iter().filter(|r| score_of(r) < score).count()
+    };
+
+    for r in rankings.iter_mut().filter(|r| r.failure.is_none()) {
+        r.size_rank = Some(place(r.stats_len, &|r| r.stats_len));
+        r.speed_rank = Some(place(r.runtime, &|r| r.runtime));
+    }
+
+    rankings.sort_by_key(|r| (r.failure.is_some(), r.size_rank));
+    rankings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use super::super::machine::{Instruction, Tile};
+
+    #[test]
+    fn report_of_a_finished_program() {
+        let m = Machine::new(vec![Instruction::Inbox, Instruction::Outbox], vec![Tile::num(7)], Default::default());
+        let report = run_report(m);
+
+        assert_eq!(report.lines().count(), 3);
+        assert!(report.ends_with("outbox 7\n"));
+    }
+
+    #[test]
+    fn report_of_a_program_that_never_finishes() {
+        let m = Machine::new(vec![Instruction::Jump(0)], vec![], Default::default());
+        let report = run_report_markdown(m);
+
+        assert_eq!(report.lines().count(), 2 + REPORT_STEP_LIMIT + 2);
+        assert!(report.ends_with(&format!("\nStopped after {} steps\n", REPORT_STEP_LIMIT)));
+    }
+
+    #[test]
+    fn rank_solutions_fails_a_program_that_never_finishes() {
+        let sources = [("loop".to_string(), "a:\nJUMP a"), ("copy".to_string(), "a:\nINBOX\nOUTBOX\nJUMP a")];
+        let rankings = rank_solutions(1, &sources);
+
+        assert_eq!(rankings[0].name, "copy");
+        assert_eq!(rankings[0].size_rank, Some(1));
+        assert_eq!(rankings[1].name, "loop");
+        assert!(rankings[1].failure.is_some());
+    }
+}